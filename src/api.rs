@@ -2,57 +2,174 @@
 use rocket::Shutdown;
 use rocket::serde::json::serde_json::json;
 use rocket::tokio::select;
-use rocket::tokio::sync::broadcast;
 use rocket::State;
-use rocket::serde::json;
-use rocket::tokio::sync::mpsc::Sender;
+use rocket::serde::{Deserialize, json::{self, Json}};
 use rocket::response::stream::{Event, EventStream};
 
-use crate::control::{ControlEvent, ControlCmd};
-use crate::endpoint_helpers::get_last_event;
-use crate::{endpoint_helpers::{query_server, await_events}};
+use std::sync::atomic::Ordering;
 
-#[get("/query")]
-pub async fn query(control: &State<Sender<ControlCmd>>) -> json::Value {
-    let status = query_server(control).await;
+use rocket::http::{ContentType, Cookie, CookieJar, SameSite, Status};
+
+use crate::auth::{Accounts, Admin, LoginError, SESSION_COOKIE, Starter, User, Viewer};
+use crate::config::Settings;
+use crate::control::ControlCmd;
+use crate::endpoint_helpers::{cancel_stop, full_stat_json, get_last_event, replayed_events, run_rcon_command, stop_server, take_backup, restore_backup};
+use crate::event_log::LastEventId;
+use crate::history::{HistoryError, Period};
+use crate::metrics::render as render_metrics;
+use crate::ratelimit::{ClientAddr, TooManyRequests};
+use crate::registry::{Registry, Server};
+use crate::schedule::{Schedule, Window, WindowMode};
+use crate::{endpoint_helpers::{query_server, await_events, await_console}};
+
+// Routes for a single server are mounted at /api/servers and begin with the server's id
+
+#[get("/servers")]
+pub async fn servers(_viewer: Viewer, registry: &State<Registry>) -> json::Value {
+    let mut servers = Vec::new();
+
+    for server in registry.iter() {
+        let event = get_last_event(&server.control).await.map(|s| s.to_event_name());
+
+        servers.push(json!({
+            "id": server.profile.id,
+            "port": server.profile.minecraft_port,
+            "last_event": event
+        }));
+    }
 
     json!({
-        "ok": status.is_some(),
-        "status": status
+        "ok": true,
+        "servers": servers
     })
 }
 
-#[get("/address")]
-pub fn address() -> String {
-    std::env::var("ADDRESS_HINT").unwrap()
+#[get("/<_>/query")]
+pub async fn query(_viewer: Viewer, server: Server<'_>) -> json::Value {
+    let response = query_server(&server.0.control).await;
+
+    json!({
+        "ok": response.is_some(),
+        "status": response.as_ref().map(|r| &r.status),
+        "full": response.as_ref().and_then(|r| r.full.as_ref()).map(full_stat_json)
+    })
 }
 
-#[post("/start")]
-pub async fn start(control: &State<Sender<ControlCmd>>) -> &'static str {
-    match control.send(ControlCmd::StartServer).await {
+#[get("/<_>/players")]
+pub async fn players(_viewer: Viewer, server: Server<'_>) -> json::Value {
+    let Some(response) = query_server(&server.0.control).await else {
+        return json!({
+            "ok": false,
+            "players": []
+        });
+    };
+
+    let (players, complete) = response.player_names();
+
+    json!({
+        "ok": true,
+        "online": response.status.players.online,
+        "max": response.status.players.max,
+        "players": players,
+        "complete": complete
+    })
+}
+
+#[get("/<_>/address")]
+pub fn address(_viewer: Viewer, server: Server<'_>, settings: &State<Settings>) -> String {
+    match server.0.profile.minecraft_port {
+        // players don't need to type the default port
+        25565 => settings.address_hint.clone(),
+        port => format!("{}:{port}", settings.address_hint),
+    }
+}
+
+#[post("/<_>/start")]
+pub async fn start(
+    starter: Starter,
+    client: ClientAddr,
+    server: Server<'_>
+) -> Result<&'static str, TooManyRequests> {
+    let server = server.0;
+    let client = client.0;
+
+    // the control thread would ignore it anyway
+    if server.schedule.mode() == Some(WindowMode::Off) {
+        return Ok("The server is scheduled to be off right now");
+    }
+    if server.backups.as_ref().is_some_and(|b| b.is_restoring()) {
+        return Ok("A backup is being restored, try again once it's done");
+    }
+
+    if let Err(retry_after) = server.limiter.try_start(client) {
+        info!("{} ({client}) was rate limited", starter.0.name);
+        return Err(TooManyRequests { retry_after });
+    }
+
+    info!("{} asked to start {}", starter.0.name, server.profile.id);
+
+    Ok(match server.control.send(ControlCmd::StartServer(starter.0.name)).await {
         Ok(_) => "Starting server...",
         Err(_) => "Unable to communicate with control thread...",
-    }
+    })
 }
 
-#[get("/start")]
+#[get("/<_>/start")]
 pub async fn start_get() -> &'static str {
     "Use a POST request to start the server"
 }
 
-#[get("/events")]
+#[post("/<_>/stop")]
+pub async fn stop(admin: Admin, server: Server<'_>) -> json::Value {
+    info!("{} asked to stop {}", admin.0.name, server.0.profile.id);
+
+    let outcome = stop_server(&server.0.control, admin.0.name).await.map(|o| o.to_result_name());
+
+    json!({
+        "ok": outcome.is_some(),
+        "result": outcome
+    })
+}
+
+#[post("/<_>/stop/cancel")]
+pub async fn stop_cancel(admin: Admin, server: Server<'_>) -> json::Value {
+    info!("{} asked to cancel stopping {}", admin.0.name, server.0.profile.id);
+
+    let cancelled = cancel_stop(&server.0.control, admin.0.name).await;
+
+    json!({
+        "ok": cancelled.is_some(),
+        "cancelled": cancelled
+    })
+}
+
+#[get("/<_>/events")]
 pub async fn events(
-    event_sub: &State<broadcast::Sender<ControlEvent>>,
+    viewer: Viewer,
+    server: Server<'_>,
+    last_event_id: LastEventId,
     mut shutdown: Shutdown
 ) -> EventStream![Event + '_] {
+
+    debug!("{} is listening to events from {}", viewer.0.name, server.0.profile.id);
     
-    let mut events = event_sub.subscribe();
+    // a reconnecting browser gets what it missed first
+    let log = &server.0.events;
+    let mut replay = log.replay(last_event_id.0);
     
     EventStream! {
+        for evt in replayed_events(&mut replay) {
+            yield evt;
+        }
+
         loop {
             select! {
-                evt = await_events(&mut events) => {
-                    if let Some(evt) = evt {
+                evts = await_events(&mut replay, log) => {
+                    let Some(evts) = evts else {
+                        break;
+                    };
+
+                    for evt in evts {
                         yield evt;
                     }
                 }
@@ -66,12 +183,362 @@ pub async fn events(
     }
 }
 
-#[get("/last-event")]
-pub async fn last_event(control: &State<Sender<ControlCmd>>) -> json::Value {
-    let event = get_last_event(control).await.map(|s| s.to_event_name());
+#[get("/<_>/console")]
+pub async fn console(
+    _admin: Admin,
+    server: Server<'_>,
+    mut shutdown: Shutdown
+) -> EventStream![Event + '_] {
+
+    let (history, mut follow) = server.0.console.subscribe();
+
+    EventStream! {
+        for line in history {
+            yield line.to_event();
+        }
+
+        loop {
+            select! {
+                line = await_console(&mut follow) => {
+                    match line {
+                        Some(line) => yield line,
+                        None => break,
+                    }
+                }
+                _ = &mut shutdown => {
+                    yield Event::empty().event("goodbye");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ConsoleCommand {
+    command: String,
+}
+
+#[post("/<_>/console/command", data = "<body>")]
+pub async fn console_command(
+    admin: Admin,
+    server: Server<'_>,
+    body: Json<ConsoleCommand>
+) -> json::Value {
+    let command = body.into_inner().command;
+    info!("{} is running a console command on {}", admin.0.name, server.0.profile.id);
+
+    match run_rcon_command(&server.0.control, command).await {
+        Some(Ok(response)) => json!({
+            "ok": true,
+            "response": response
+        }),
+        Some(Err(e)) => json!({
+            "ok": false,
+            "error": e.to_string()
+        }),
+        None => json!({
+            "ok": false,
+            "error": "Unable to communicate with control thread"
+        }),
+    }
+}
+
+#[get("/<_>/last-event")]
+pub async fn last_event(_viewer: Viewer, server: Server<'_>) -> json::Value {
+    let event = get_last_event(&server.0.control).await;
     
     json!({
         "ok": event.is_some(),
-        "last_event": event
+        "last_event": event.as_ref().map(|e| e.to_event_name()),
+        "data": event.as_ref().map(|e| e.to_event_data())
+    })
+}
+
+/// Clients connected through the proxy. Admin only, since this includes their addresses.
+#[get("/<_>/connections")]
+pub fn connections(_admin: Admin, server: Server<'_>) -> json::Value {
+    let Some(proxy) = &server.0.proxy else {
+        return json!({
+            "ok": false,
+            "error": "The proxy is not enabled for this server"
+        });
+    };
+
+    let connections: Vec<_> = proxy.connections().iter().map(|c| json!({
+        "client": c.client.ip(),
+        "player": c.player,
+        "connected_secs": c.since.elapsed().as_secs(),
+        "bytes_in": c.bytes_in.load(Ordering::Relaxed),
+        "bytes_out": c.bytes_out.load(Ordering::Relaxed)
+    })).collect();
+
+    json!({
+        "ok": true,
+        "connections": connections
+    })
+}
+
+/// When the server was running and who started and stopped it. `from` and `to` are in
+/// milliseconds since the Unix epoch, like event timestamps.
+#[get("/<_>/history/sessions?<period..>")]
+pub async fn history_sessions(_viewer: Viewer, server: Server<'_>, period: Period) -> json::Value {
+    let Some(history) = &server.0.history else {
+        return history_off();
+    };
+
+    match history.sessions(&server.0.profile.id, period).await {
+        Ok(sessions) => json!({
+            "ok": true,
+            "sessions": sessions
+        }),
+        Err(e) => history_failed(e),
+    }
+}
+
+/// Who played and how many players were online
+#[get("/<_>/history/players?<period..>")]
+pub async fn history_players(_viewer: Viewer, server: Server<'_>, period: Period) -> json::Value {
+    let Some(history) = &server.0.history else {
+        return history_off();
+    };
+
+    match history.players(&server.0.profile.id, period).await {
+        Ok((players, samples)) => json!({
+            "ok": true,
+            "players": players,
+            "samples": samples
+        }),
+        Err(e) => history_failed(e),
+    }
+}
+
+/// Everything the server's control thread said
+#[get("/<_>/history/events?<period..>")]
+pub async fn history_events(_viewer: Viewer, server: Server<'_>, period: Period) -> json::Value {
+    let Some(history) = &server.0.history else {
+        return history_off();
+    };
+
+    match history.events(&server.0.profile.id, period).await {
+        Ok(events) => json!({
+            "ok": true,
+            "events": events
+        }),
+        Err(e) => history_failed(e),
+    }
+}
+
+fn history_off() -> json::Value {
+    json!({
+        "ok": false,
+        "error": "The history is turned off"
+    })
+}
+
+fn history_failed(e: HistoryError) -> json::Value {
+    error!("{e}");
+
+    json!({
+        "ok": false,
+        "error": "Unable to read the history"
+    })
+}
+
+/// The world backups that are kept, newest first
+#[get("/<_>/backups")]
+pub async fn backups(_viewer: Viewer, server: Server<'_>) -> json::Value {
+    let Some(backups) = &server.0.backups else {
+        return backups_off();
+    };
+
+    match backups.list().await {
+        Ok(backups) => json!({
+            "ok": true,
+            "backups": backups
+        }),
+        Err(e) => {
+            error!("Unable to list the backups of {}: {e}", server.0.profile.id);
+
+            json!({
+                "ok": false,
+                "error": "Unable to list the backups"
+            })
+        },
+    }
+}
+
+/// Back the world up now, answering once the backup is done
+#[post("/<_>/backups")]
+pub async fn create_backup(admin: Admin, server: Server<'_>) -> json::Value {
+    if server.0.backups.is_none() {
+        return backups_off();
+    }
+
+    match take_backup(&server.0.control, admin.0.name).await {
+        Some(Ok(backup)) => json!({
+            "ok": true,
+            "backup": backup
+        }),
+        Some(Err(e)) => json!({
+            "ok": false,
+            "error": e.to_string()
+        }),
+        None => json!({
+            "ok": false,
+            "error": "Unable to communicate with control thread..."
+        }),
+    }
+}
+
+/// Put a backup in place of the world, after backing the world up. Minecraft must be offline.
+#[post("/<_>/backups/<id>/restore")]
+pub async fn restore(admin: Admin, server: Server<'_>, id: &str) -> json::Value {
+    if server.0.backups.is_none() {
+        return backups_off();
+    }
+
+    match restore_backup(&server.0.control, admin.0.name, id.to_string()).await {
+        Some(Ok(restore)) => json!({
+            "ok": true,
+            "restored": restore.backup,
+            "safety_backup": restore.safety_backup
+        }),
+        Some(Err(e)) => json!({
+            "ok": false,
+            "error": e.to_string()
+        }),
+        None => json!({
+            "ok": false,
+            "error": "Unable to communicate with control thread..."
+        }),
+    }
+}
+
+fn backups_off() -> json::Value {
+    json!({
+        "ok": false,
+        "error": "Backups are turned off"
+    })
+}
+
+/// When the server is kept on or off
+#[get("/<_>/schedule")]
+pub fn schedule(_viewer: Viewer, server: Server<'_>) -> json::Value {
+    schedule_json(&server.0.schedule)
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ScheduleUpdate {
+    windows: Vec<Window>,
+}
+
+/// Replace the server's windows until the application restarts
+#[put("/<_>/schedule", data = "<body>")]
+pub fn set_schedule(
+    admin: Admin,
+    server: Server<'_>,
+    body: Result<Json<ScheduleUpdate>, json::Error<'_>>
+) -> json::Value {
+    // say which cron expression is wrong
+    let body = match body {
+        Ok(body) => body.into_inner(),
+        Err(e) => return json!({
+            "ok": false,
+            "error": e.to_string()
+        }),
+    };
+
+    info!("{} changed the schedule of {}", admin.0.name, server.0.profile.id);
+
+    server.0.schedule.set_windows(body.windows);
+
+    schedule_json(&server.0.schedule)
+}
+
+fn schedule_json(schedule: &Schedule) -> json::Value {
+    json!({
+        "ok": true,
+        "mode": schedule.mode(),
+        "windows": schedule.windows()
+    })
+}
+
+/// Every server's metrics for Prometheus
+#[get("/metrics")]
+pub fn metrics(_viewer: Viewer, registry: &State<Registry>) -> (ContentType, String) {
+    let servers = registry.iter().map(|s| (s.profile.id.as_str(), &*s.metrics));
+
+    // version 0.0.4 of the text exposition format
+    let content_type = ContentType::new("text", "plain").with_params(("version", "0.0.4"));
+
+    (content_type, render_metrics(servers))
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+/// Why a login was refused
+#[derive(Responder)]
+pub enum LoginRefused {
+    Invalid(Status),
+    Throttled(TooManyRequests),
+}
+
+#[post("/login", data = "<credentials>")]
+pub fn login(
+    accounts: &State<Accounts>,
+    cookies: &CookieJar<'_>,
+    client: ClientAddr,
+    credentials: Json<Credentials>
+) -> Result<json::Value, LoginRefused> {
+    let (token, user) = match accounts.login(client.0, &credentials.username, &credentials.password) {
+        Ok(session) => session,
+        Err(LoginError::Invalid) => return Err(LoginRefused::Invalid(Status::Unauthorized)),
+        Err(LoginError::Throttled(retry_after)) => return Err(LoginRefused::Throttled(TooManyRequests { retry_after })),
+    };
+
+    // Lax rather than Strict, so that following a link to the web interface keeps the session. The
+    // cookie is only sent cross-origin to the same site (e.g. another port), see CORS_ORIGIN.
+    cookies.add(Cookie::build((SESSION_COOKIE, token))
+        .http_only(true)
+        .same_site(SameSite::Lax));
+
+    Ok(json!({
+        "ok": true,
+        "name": user.name,
+        "role": user.role.to_role_name()
+    }))
+}
+
+/// Answer CORS preflight requests for every route. The `Cors` fairing adds the headers.
+#[options("/<_..>")]
+pub fn preflight() -> Status {
+    Status::NoContent
+}
+
+#[post("/logout")]
+pub fn logout(accounts: &State<Accounts>, cookies: &CookieJar<'_>) -> json::Value {
+    if let Some(cookie) = cookies.get(SESSION_COOKIE) {
+        accounts.logout(cookie.value());
+    }
+
+    cookies.remove(Cookie::from(SESSION_COOKIE));
+
+    json!({ "ok": true })
+}
+
+#[get("/whoami")]
+pub fn whoami(user: User) -> json::Value {
+    json!({
+        "ok": true,
+        "name": user.name,
+        "role": user.role.to_role_name()
     })
-}
\ No newline at end of file
+}
@@ -12,6 +12,12 @@ pub enum Method {
     Retry(u64)
 }
 
+/// How long to wait before the `n`th retry (counting from 0) when backing off exponentially.
+/// The delay doubles every retry, starting at `initial` and never exceeding `max`.
+pub fn backoff_delay(initial: Duration, max: Duration, n: u32) -> Duration {
+    initial.saturating_mul(2u32.saturating_pow(n)).min(max)
+}
+
 /// Call a function multiple times until a failure condition is met.
 /// 
 /// The function must return a future that yields a `Result` type.
@@ -0,0 +1,390 @@
+// Accounts, sessions and request guards for endpoints that shouldn't be open to the public
+
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::{SaltString, rand_core::{OsRng, RngCore}};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// Name of the cookie holding a login session
+pub const SESSION_COOKIE: &str = "session";
+
+const SESSION_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How many wrong passwords a client, or a user, gets per LOGIN_FAILURE_WINDOW
+const LOGIN_MAX_FAILURES: u32 = 5;
+const LOGIN_FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// What a user is allowed to do. Each role can do everything the roles before it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Query the server and listen to events
+    Viewer,
+    /// Start the server
+    Starter,
+    /// Stop the server, use the console and change configuration
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "viewer" => Ok(Role::Viewer),
+            "starter" => Ok(Role::Starter),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Role {
+    pub fn to_role_name(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Starter => "starter",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+/// Someone who has been authenticated (or an anonymous visitor, if anonymous access is allowed)
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+}
+
+struct PasswordEntry {
+    role: Role,
+    hash: String,
+}
+
+struct Session {
+    user: User,
+    expires: Instant,
+}
+
+/// Who failed to log in, counted both by client and by the user they tried to log in as
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum FailureKey {
+    Client(IpAddr),
+    User(String),
+}
+
+/// Failed logins since the first one in the current window
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+/// Everyone who can log in, plus the sessions of those who have
+pub struct Accounts {
+    passwords: HashMap<String, PasswordEntry>,
+    /// API tokens, keyed by their SHA-256 hash
+    tokens: HashMap<String, User>,
+    sessions: Mutex<HashMap<String, Session>>,
+    failures: Mutex<HashMap<FailureKey, Failures>>,
+    anonymous: Option<Role>,
+}
+
+/// Describes the ways in which logging in can fail
+#[derive(Debug)]
+pub enum LoginError {
+    /// Unknown user or wrong password
+    Invalid,
+    /// Too many failed attempts from this client or for this user. Holds how long until the next
+    /// attempt is allowed.
+    Throttled(Duration),
+}
+
+/// Describes the ways in which the users file can be invalid
+#[derive(Debug, Error)]
+pub enum UsersFileError {
+    #[error("Unable to read the users file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Line {0} of the users file should look like `<user|token> <name> <viewer|starter|admin> <hash>`")]
+    Syntax(usize),
+    #[error("Line {0} of the users file has an invalid password hash")]
+    InvalidHash(usize),
+}
+
+impl Accounts {
+    /// Load accounts from the users file at `path`. Each non-empty line that doesn't start with `#`
+    /// is one of:
+    /// - `user <name> <role> <argon2 hash>` for someone who logs in with a password
+    /// - `token <name> <role> <sha-256 hash>` for a script that sends an API token
+    ///
+    /// Use the `hash-password` and `new-token` subcommands to generate the hashes.
+    ///
+    /// A missing file is treated as empty, so only anonymous access is possible.
+    pub fn load(path: &str, anonymous: Option<Role>) -> Result<Self, UsersFileError> {
+        use UsersFileError::*;
+
+        let mut accounts = Accounts {
+            passwords: HashMap::new(),
+            tokens: HashMap::new(),
+            sessions: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+            anonymous,
+        };
+
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!("Users file {path} does not exist, nobody will be able to log in");
+                return Ok(accounts);
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        for (i, line) in std::io::BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            let line_number = i + 1;
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let [kind, name, role, hash] = fields[..] else {
+                return Err(Syntax(line_number));
+            };
+            let role = role.parse().map_err(|_| Syntax(line_number))?;
+
+            match kind {
+                "user" => {
+                    PasswordHash::new(hash).map_err(|_| InvalidHash(line_number))?;
+
+                    accounts.passwords.insert(name.to_string(), PasswordEntry { role, hash: hash.to_string() });
+                },
+                "token" => {
+                    let user = User { name: name.to_string(), role };
+
+                    accounts.tokens.insert(hash.to_lowercase(), user);
+                },
+                _ => return Err(Syntax(line_number)),
+            }
+        }
+
+        info!("Loaded {} users and {} API tokens", accounts.passwords.len(), accounts.tokens.len());
+
+        Ok(accounts)
+    }
+
+    /// Check a username and password sent by `client`, and start a new session if they're right.
+    /// Returns the session token.
+    ///
+    /// After LOGIN_MAX_FAILURES failures from the same client or for the same user, attempts are
+    /// refused without checking the password until LOGIN_FAILURE_WINDOW has passed.
+    pub fn login(&self, client: IpAddr, name: &str, password: &str) -> Result<(String, User), LoginError> {
+        let keys = [FailureKey::Client(client), FailureKey::User(name.to_string())];
+
+        if let Some(wait) = self.throttled(&keys) {
+            warn!("Refused a login attempt for {name} from {client}, too many failures");
+            return Err(LoginError::Throttled(wait));
+        }
+
+        let entry = self.passwords.get(name).filter(|entry| {
+            PasswordHash::new(&entry.hash)
+                .is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+        });
+
+        let Some(entry) = entry else {
+            warn!("Failed login attempt for {name} from {client}");
+            self.count_failure(keys);
+            return Err(LoginError::Invalid);
+        };
+
+        // a successful login starts over
+        {
+            let mut failures = self.failures.lock().unwrap();
+            for key in &keys {
+                failures.remove(key);
+            }
+        }
+
+        let user = User { name: name.to_string(), role: entry.role };
+        let token = random_token();
+
+        let mut sessions = self.sessions.lock().unwrap();
+
+        // a convenient time to forget about old sessions
+        let now = Instant::now();
+        sessions.retain(|_, s| s.expires > now);
+
+        sessions.insert(token.clone(), Session { user: user.clone(), expires: now + SESSION_LIFETIME });
+
+        info!("{name} logged in");
+
+        Ok((token, user))
+    }
+
+    /// How long until `keys` may try to log in again, if they've failed too often
+    fn throttled(&self, keys: &[FailureKey]) -> Option<Duration> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+
+        keys.iter()
+            .filter_map(|key| failures.get(key))
+            .filter(|f| f.count >= LOGIN_MAX_FAILURES)
+            .map(|f| f.since + LOGIN_FAILURE_WINDOW)
+            .filter(|until| *until > now)
+            .max()
+            .map(|until| until - now)
+    }
+
+    fn count_failure(&self, keys: [FailureKey; 2]) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+
+        // a convenient time to forget about old failures
+        failures.retain(|_, f| now - f.since < LOGIN_FAILURE_WINDOW);
+
+        for key in keys {
+            failures.entry(key).or_insert(Failures { count: 0, since: now }).count += 1;
+        }
+    }
+
+    pub fn logout(&self, token: &str) {
+        self.sessions.lock().unwrap().remove(token);
+    }
+
+    fn session_user(&self, token: &str) -> Option<User> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(token)?;
+
+        (session.expires > Instant::now()).then(|| session.user.clone())
+    }
+
+    fn token_user(&self, token: &str) -> Option<User> {
+        self.tokens.get(&sha256_hex(token)).cloned()
+    }
+}
+
+/// Describes the ways in which a request can fail to authenticate
+#[derive(Debug)]
+pub enum AuthError {
+    /// The request carried no credentials and anonymous access is disabled
+    Missing,
+    /// The request carried an unknown token or an expired session
+    Invalid,
+    /// The user is authenticated, but their role doesn't allow this
+    Forbidden,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+    type Error = AuthError;
+
+    /// Authenticate with an `Authorization: Bearer <token>` header or a session cookie, falling
+    /// back on anonymous access
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        use AuthError::*;
+
+        let Some(accounts) = request.rocket().state::<Accounts>() else {
+            error!("Accounts are not being managed by Rocket");
+            return Outcome::Error((Status::InternalServerError, Invalid));
+        };
+
+        if let Some(header) = request.headers().get_one("Authorization") {
+            return match header.strip_prefix("Bearer ").and_then(|t| accounts.token_user(t.trim())) {
+                Some(user) => Outcome::Success(user),
+                None => {
+                    warn!("Rejected a request with an invalid API token");
+                    Outcome::Error((Status::Unauthorized, Invalid))
+                },
+            };
+        }
+
+        if let Some(cookie) = request.cookies().get(SESSION_COOKIE) {
+            return match accounts.session_user(cookie.value()) {
+                Some(user) => Outcome::Success(user),
+                None => Outcome::Error((Status::Unauthorized, Invalid)),
+            };
+        }
+
+        match accounts.anonymous {
+            Some(role) => Outcome::Success(User { name: String::from("anonymous"), role }),
+            None => Outcome::Error((Status::Unauthorized, Missing)),
+        }
+    }
+}
+
+/// Authenticate a request and make sure the user has at least `role`
+async fn require_role(request: &Request<'_>, role: Role) -> Outcome<User, AuthError> {
+    match request.guard::<User>().await {
+        Outcome::Success(user) if user.role >= role => Outcome::Success(user),
+        Outcome::Success(user) => {
+            warn!("{} ({}) is not allowed to do that", user.name, user.role.to_role_name());
+            Outcome::Error((Status::Forbidden, AuthError::Forbidden))
+        },
+        other => other,
+    }
+}
+
+/// A request guard for each role. Succeeds for users with that role or higher.
+macro_rules! role_guard {
+    ($guard:ident, $role:expr) => {
+        pub struct $guard(pub User);
+
+        #[rocket::async_trait]
+        impl<'r> FromRequest<'r> for $guard {
+            type Error = AuthError;
+
+            async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+                require_role(request, $role).await.map($guard)
+            }
+        }
+    };
+}
+
+role_guard!(Viewer, Role::Viewer);
+role_guard!(Starter, Role::Starter);
+role_guard!(Admin, Role::Admin);
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+
+    hex::encode(bytes)
+}
+
+fn sha256_hex(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// `hash-password` subcommand: read a password from stdin and print its hash for the users file
+pub fn print_password_hash() -> Result<(), Box<dyn std::error::Error>> {
+    eprintln!("Enter a password:");
+
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)
+        .map_err(|e| e.to_string())?;
+
+    println!("{hash}");
+
+    Ok(())
+}
+
+/// `new-token` subcommand: generate an API token and print it along with its hash
+pub fn print_new_token() {
+    let token = random_token();
+
+    println!("token:  {token}");
+    println!("hash:   {}", sha256_hex(&token));
+}
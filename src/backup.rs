@@ -0,0 +1,523 @@
+// Backups of a server's world directories, with rules for how many are kept. They're either
+// compressed archives or deduplicated snapshots (see chunk_store).
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use chrono::{Local, NaiveDateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use rocket::serde::Serialize;
+use rocket::tokio::{self, sync::{oneshot, Mutex, OwnedMutexGuard}, task::{JoinError, JoinHandle}};
+use thiserror::Error;
+
+use crate::chunk_store::ChunkStore;
+use crate::config::{ServerProfile, Settings};
+
+const ARCHIVE_EXTENSION: &str = ".tar.gz";
+/// Archives are written under this name and renamed once they're complete
+const PARTIAL_EXTENSION: &str = ".tar.gz.partial";
+/// Ids begin with when the backup was taken, in UTC
+const ID_TIME_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+/// Why a backup was taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum BackupKind {
+    /// Minecraft stopped
+    Stop,
+    /// BACKUP_INTERVAL passed while Minecraft was running
+    Timer,
+    /// Somebody asked for it
+    Manual,
+    /// The world as it was before a backup was restored
+    Safety,
+}
+
+impl BackupKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            BackupKind::Stop => "stop",
+            BackupKind::Timer => "timer",
+            BackupKind::Manual => "manual",
+            BackupKind::Safety => "safety",
+        }
+    }
+}
+
+impl FromStr for BackupKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stop" => Ok(BackupKind::Stop),
+            "timer" => Ok(BackupKind::Timer),
+            "manual" => Ok(BackupKind::Manual),
+            "safety" => Ok(BackupKind::Safety),
+            _ => Err(()),
+        }
+    }
+}
+
+/// One archive or snapshot in the backup directory
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Backup {
+    /// The archive's or snapshot's file name without the extension
+    pub id: String,
+    pub kind: BackupKind,
+    /// Milliseconds since the Unix epoch
+    pub created: i64,
+    /// Size of the archive in bytes, or of the world in a snapshot
+    pub size: u64,
+    /// Whether this is a snapshot in the chunk store rather than an archive
+    pub deduplicated: bool,
+}
+
+/// A backup that was put in place of the world
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Restore {
+    pub backup: Backup,
+    /// The world from before, unless there wasn't one
+    pub safety_backup: Option<Backup>,
+}
+
+/// Describes the ways in which taking or restoring a backup can fail
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("Backups are turned off")]
+    Off,
+    #[error("A backup is already being taken")]
+    Busy,
+    #[error("No world directories were found in {0}")]
+    NoWorlds(String),
+    #[error("Unable to have Minecraft stop saving: {0}")]
+    SaveOff(String),
+    #[error("There is no backup `{0}`")]
+    NotFound(String),
+    #[error("Minecraft must be offline to restore a backup")]
+    Running,
+    #[error("Minecraft is starting, try again once it's up")]
+    Starting,
+    #[error("Unable to write the backup: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Backup task failed: {0}")]
+    Task(#[from] JoinError),
+}
+
+/// A backup being taken in the background
+pub type BackupTask = JoinHandle<Result<Backup, BackupError>>;
+/// Where to send a backup once it's done
+pub type BackupReply = oneshot::Sender<Result<Backup, BackupError>>;
+/// A backup being restored in the background
+pub type RestoreTask = JoinHandle<Result<Restore, BackupError>>;
+/// Where to send a restore once it's done
+pub type RestoreReply = oneshot::Sender<Result<Restore, BackupError>>;
+
+/// One server's backups
+pub struct Backups {
+    server: String,
+    server_path: PathBuf,
+    /// This server's own folder in the backup directory
+    directory: PathBuf,
+    worlds: Option<Vec<String>>,
+    keep_last: usize,
+    keep_daily: u64,
+    /// Take snapshots in `store` rather than archives
+    deduplicate: bool,
+    store: ChunkStore,
+    /// Held while an archive is written, so that backups are taken one at a time and Minecraft
+    /// doesn't start in the middle of one
+    busy: Arc<Mutex<()>>,
+    /// Set while a backup is being restored, during which Minecraft isn't started at all
+    restoring: AtomicBool,
+}
+
+impl Backups {
+    /// Returns `None` if the profile doesn't have backups turned on
+    pub fn new(profile: &ServerProfile) -> Option<Arc<Self>> {
+        let directory = profile.backup_directory.as_ref()?.join(&profile.id);
+
+        Some(Arc::new(Backups {
+            server: profile.id.clone(),
+            server_path: PathBuf::from(&profile.server_path),
+            store: ChunkStore::new(&directory),
+            directory,
+            worlds: profile.backup_worlds.clone(),
+            keep_last: profile.backup_keep_last,
+            keep_daily: profile.backup_keep_daily,
+            deduplicate: profile.backup_deduplicate,
+            busy: Arc::new(Mutex::new(())),
+            restoring: AtomicBool::new(false),
+        }))
+    }
+
+    /// Reserve the world for a backup, unless one is already being taken
+    pub fn try_claim(&self) -> Result<OwnedMutexGuard<()>, BackupError> {
+        self.busy.clone().try_lock_owned().map_err(|_| BackupError::Busy)
+    }
+
+    /// Wait for the backup being taken (if any) to finish
+    pub async fn wait(&self) {
+        if self.busy.try_lock().is_err() {
+            info!("Waiting for the backup of {} to finish", self.server);
+        }
+
+        drop(self.busy.lock().await);
+    }
+
+    /// Archive the world directories in the background, and then remove the backups that are no
+    /// longer kept. The world stays reserved until it's all done, so that nothing else touches the
+    /// backup directory in the meantime either.
+    pub fn create(self: &Arc<Self>, kind: BackupKind, claim: OwnedMutexGuard<()>) -> BackupTask {
+        let backups = self.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let backup = backups.write_archive(kind)?;
+
+            if let Err(e) = backups.prune(&backup.id) {
+                warn!("Unable to remove old backups of {}: {e}", backups.server);
+            }
+
+            drop(claim);
+
+            Ok(backup)
+        })
+    }
+
+    pub fn is_restoring(&self) -> bool {
+        self.restoring.load(Ordering::SeqCst)
+    }
+
+    /// Back the current world up, and then replace its directories with the ones in the backup
+    /// `id` in the background. Minecraft must be offline, and isn't started until it's done.
+    pub fn restore(self: &Arc<Self>, id: String, claim: OwnedMutexGuard<()>) -> RestoreTask {
+        let backups = self.clone();
+        self.restoring.store(true, Ordering::SeqCst);
+
+        tokio::task::spawn_blocking(move || {
+            let restore = backups.restore_archive(&id);
+            backups.restoring.store(false, Ordering::SeqCst);
+            drop(claim);
+
+            restore
+        })
+    }
+
+    /// Every backup of the server, newest first
+    pub async fn list(self: &Arc<Self>) -> Result<Vec<Backup>, BackupError> {
+        let backups = self.clone();
+
+        tokio::task::spawn_blocking(move || backups.read_directory()).await?
+    }
+
+    fn write_archive(&self, kind: BackupKind) -> Result<Backup, BackupError> {
+        let worlds = self.world_directories();
+        if worlds.is_empty() {
+            return Err(BackupError::NoWorlds(self.server_path.display().to_string()));
+        }
+
+        fs::create_dir_all(&self.directory)?;
+
+        let now = Utc::now();
+        let id = format!("{}-{}", now.format(ID_TIME_FORMAT), kind.as_str());
+
+        let size = if self.deduplicate {
+            self.store.snapshot(&id, &self.server_path, &worlds)?
+        } else {
+            let partial = self.directory.join(format!("{id}{PARTIAL_EXTENSION}"));
+
+            let written = write_tar_gz(&partial, &self.server_path, &worlds);
+            if let Err(e) = written {
+                let _ = fs::remove_file(&partial);
+                return Err(e.into());
+            }
+
+            let path = self.directory.join(format!("{id}{ARCHIVE_EXTENSION}"));
+            fs::rename(&partial, &path)?;
+
+            fs::metadata(&path)?.len()
+        };
+
+        info!("Backed up {} as {id}", self.server);
+
+        Ok(Backup { id, kind, created: now.timestamp_millis(), size, deduplicated: self.deduplicate })
+    }
+
+    fn restore_archive(&self, id: &str) -> Result<Restore, BackupError> {
+        // only ids that are listed are looked up, so that they can't point anywhere else
+        let backup = self.read_directory()?.into_iter()
+            .find(|backup| backup.id == id)
+            .ok_or_else(|| BackupError::NotFound(id.to_string()))?;
+
+        let safety_backup = match self.write_archive(BackupKind::Safety) {
+            Ok(safety_backup) => Some(safety_backup),
+            // there's no world to lose
+            Err(BackupError::NoWorlds(_)) => None,
+            Err(e) => return Err(e),
+        };
+
+        // every world is replaced, even the ones the backup doesn't have, so that an archive
+        // without the nether doesn't leave the current nether beside the old overworld
+        let clear = self.world_directories();
+
+        // unpacked next to the world directories so that they can be swapped by renaming
+        let staging = self.server_path.join(format!(".restore-{id}"));
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+
+        let unpacked = if backup.deduplicated {
+            self.store.unpack(id, &staging)
+        } else {
+            unpack_tar_gz(&self.directory.join(format!("{id}{ARCHIVE_EXTENSION}")), &staging)
+        };
+        let unpacked = unpacked.and_then(|()| swap_worlds(&self.server_path, &staging, &clear, id));
+
+        let _ = fs::remove_dir_all(&staging);
+        unpacked?;
+
+        info!("Restored the backup {id} of {}", self.server);
+
+        Ok(Restore { backup, safety_backup })
+    }
+
+    /// The world directories that exist: the configured ones, or else the level named in
+    /// server.properties along with the separate nether and end directories some servers use
+    fn world_directories(&self) -> Vec<String> {
+        let worlds = self.worlds.clone().unwrap_or_else(|| {
+            let level = level_name(&self.server_path);
+            vec![format!("{level}_nether"), format!("{level}_the_end"), level]
+        });
+
+        worlds.into_iter().filter(|world| self.server_path.join(world).is_dir()).collect()
+    }
+
+    fn read_directory(&self) -> Result<Vec<Backup>, BackupError> {
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            // nothing has been backed up yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut backups = Vec::new();
+
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            // other files in the directory aren't ours
+            let Some(backup) = name.strip_suffix(ARCHIVE_EXTENSION).and_then(parse_id) else {
+                continue;
+            };
+
+            backups.push(Backup { size: entry.metadata()?.len(), ..backup });
+        }
+
+        // snapshots are listed even if new backups are archives, and the other way around
+        for (id, size) in self.store.snapshots()? {
+            if let Some(backup) = parse_id(&id) {
+                backups.push(Backup { size, deduplicated: true, ..backup });
+            }
+        }
+
+        backups.sort_by_key(|backup| std::cmp::Reverse(backup.created));
+
+        Ok(backups)
+    }
+
+    /// Remove the backups that aren't among the `keep_last` newest, or the newest of their day in
+    /// the last `keep_daily` days. The backup `newest` was just taken and is always kept, as are
+    /// safety backups, which don't count towards either. Blobs that only removed snapshots used are
+    /// removed too.
+    fn prune(&self, newest: &str) -> Result<(), BackupError> {
+        let today = Local::now().date_naive();
+        let mut days_kept = HashSet::new();
+        let mut removed_snapshot = false;
+
+        // a safety backup is the only copy of the world a restore replaced, so it's left for
+        // somebody to remove by hand
+        let backups = self.read_directory()?.into_iter()
+            .filter(|backup| backup.kind != BackupKind::Safety);
+
+        for (i, backup) in backups.enumerate() {
+            let Some(day) = chrono::DateTime::from_timestamp_millis(backup.created)
+                .map(|created| created.with_timezone(&Local).date_naive()) else {
+                continue;
+            };
+
+            // the list is newest first, so the first backup seen for a day is its newest
+            let newest_of_day = days_kept.insert(day);
+            let recent_day = (today - day).num_days() < self.keep_daily as i64;
+
+            if backup.id == newest || i < self.keep_last || (newest_of_day && recent_day) {
+                continue;
+            }
+
+            info!("Removing the backup {} of {}", backup.id, self.server);
+
+            if backup.deduplicated {
+                self.store.remove(&backup.id)?;
+                removed_snapshot = true;
+            } else {
+                fs::remove_file(self.directory.join(format!("{}{ARCHIVE_EXTENSION}", backup.id)))?;
+            }
+        }
+
+        if removed_snapshot {
+            let garbage = self.store.collect_garbage()?;
+            info!("Removed {} unused blobs ({} bytes) and {} unfinished files of {}", garbage.blobs, garbage.bytes, garbage.partials, self.server);
+        }
+
+        Ok(())
+    }
+}
+
+/// `gc-backups` subcommand: remove the blobs that no snapshot refers to, for every server whose
+/// backups are turned on
+pub fn print_garbage_collection(settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    for backups in settings.servers.iter().filter_map(Backups::new) {
+        let garbage = backups.store.collect_garbage()?;
+        println!("{}: removed {} blobs ({} bytes) and {} unfinished files", backups.server, garbage.blobs, garbage.bytes, garbage.partials);
+    }
+
+    Ok(())
+}
+
+/// Log how a backup went and tell whoever asked for it
+pub fn report(server: &str, result: Result<Result<Backup, BackupError>, JoinError>, reply: Option<BackupReply>) {
+    let result = result.map_err(BackupError::from).and_then(|backup| backup);
+
+    if let Err(e) = &result {
+        error!("Unable to back up {server}: {e}");
+    }
+
+    if let Some(reply) = reply {
+        if reply.send(result).is_err() {
+            error!("Webserver did not get the backup (receiver hung up)");
+        }
+    }
+}
+
+/// `report` on a backup once it's done
+pub async fn finish(server: String, task: BackupTask, reply: Option<BackupReply>) {
+    report(&server, task.await, reply);
+}
+
+fn write_tar_gz(path: &Path, server_path: &Path, worlds: &[String]) -> std::io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+    for world in worlds {
+        archive.append_dir_all(world, server_path.join(world))?;
+    }
+
+    archive.into_inner()?.finish()?.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+fn unpack_tar_gz(path: &Path, destination: &Path) -> std::io::Result<()> {
+    if destination.exists() {
+        fs::remove_dir_all(destination)?;
+    }
+    fs::create_dir_all(destination)?;
+
+    let file = BufReader::new(File::open(path)?);
+
+    // entries that would end up outside of the destination are skipped
+    tar::Archive::new(GzDecoder::new(file)).unpack(destination)
+}
+
+/// Move every directory in `staging` into `server_path`, in place of the directory there of the
+/// same name, and move the worlds in `clear` that `staging` doesn't have out of the way. Either
+/// every directory is replaced or none are.
+fn swap_worlds(server_path: &Path, staging: &Path, clear: &[String], id: &str) -> std::io::Result<()> {
+    let replaced = server_path.join(format!(".replaced-{id}"));
+    if replaced.exists() {
+        fs::remove_dir_all(&replaced)?;
+    }
+    fs::create_dir_all(&replaced)?;
+
+    let mut worlds = Vec::new();
+    for entry in fs::read_dir(staging)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            worlds.push(entry.file_name());
+        }
+    }
+    for world in clear {
+        if !worlds.iter().any(|staged| staged == world.as_str()) {
+            worlds.push(world.into());
+        }
+    }
+
+    // the worlds that have been swapped, and whether there was one in the way
+    let mut swapped = Vec::new();
+
+    for world in &worlds {
+        let current = server_path.join(world);
+        let existed = current.exists();
+
+        let swap = (|| {
+            if existed {
+                fs::rename(&current, replaced.join(world))?;
+            }
+            let staged = staging.join(world);
+            if staged.exists() {
+                fs::rename(staged, &current)?;
+            }
+            Ok(())
+        })();
+
+        if let Err(e) = swap {
+            // put back what was there before
+            if existed && !current.exists() {
+                let _ = fs::rename(replaced.join(world), &current);
+            }
+            for (world, existed) in swapped {
+                let current = server_path.join(world);
+                let _ = fs::remove_dir_all(&current);
+                if existed {
+                    let _ = fs::rename(replaced.join(world), &current);
+                }
+            }
+
+            return Err(e);
+        }
+
+        swapped.push((world, existed));
+    }
+
+    fs::remove_dir_all(&replaced)
+}
+
+/// The `level-name` from server.properties, which is the world Minecraft loads
+fn level_name(server_path: &Path) -> String {
+    let properties = fs::read_to_string(server_path.join("server.properties")).unwrap_or_default();
+
+    properties.lines()
+        .find_map(|line| line.strip_prefix("level-name="))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .unwrap_or("world")
+        .to_string()
+}
+
+/// Work out what a backup is from its id
+fn parse_id(id: &str) -> Option<Backup> {
+    let (time, kind) = id.split_once('-')?;
+    let created = NaiveDateTime::parse_from_str(time, ID_TIME_FORMAT).ok()?.and_utc();
+
+    Some(Backup {
+        id: id.to_string(),
+        kind: kind.parse().ok()?,
+        created: created.timestamp_millis(),
+        size: 0,
+        deduplicated: false,
+    })
+}
@@ -0,0 +1,469 @@
+// Deduplicated backups: snapshots of the world whose file contents are kept in a content-addressed
+// store, so that what didn't change between snapshots is only stored once
+//
+// Region files (.mca) are split along the chunks in them, since most chunks don't change between
+// snapshots even when the region they're in does. A region file is stored as a recipe, a blob
+// listing the blobs to concatenate, and other files are stored whole.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use rocket::serde::{Deserialize, Serialize, json};
+use sha2::{Digest, Sha256};
+
+/// Region files are made of 4 KiB sectors, and begin with two sectors of chunk locations and
+/// timestamps
+const SECTOR: usize = 4096;
+const REGION_HEADER: usize = 2 * SECTOR;
+
+/// Blobs this recently written or used are kept by garbage collection even if nothing refers to
+/// them, as a snapshot being taken may be about to. Partly written blobs and manifests are kept
+/// as long, in case they're still being written.
+const GC_GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+
+const PARTIAL_EXTENSION: &str = "partial";
+
+/// What a snapshot holds, relative to the server directory
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Manifest {
+    /// Total size of the files in bytes
+    size: u64,
+    /// Every directory, so that empty ones are restored too
+    directories: Vec<String>,
+    files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ManifestFile {
+    path: String,
+    /// The file's contents, or its recipe if it's chunked
+    blob: String,
+    chunked: bool,
+}
+
+/// How much garbage collection removed
+pub struct Garbage {
+    pub blobs: usize,
+    pub bytes: u64,
+    /// Blobs and manifests left partly written by a snapshot that failed
+    pub partials: usize,
+}
+
+/// Snapshots and blobs kept in one directory
+pub struct ChunkStore {
+    snapshots: PathBuf,
+    blobs: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn new(directory: &Path) -> Self {
+        ChunkStore {
+            snapshots: directory.join("snapshots"),
+            blobs: directory.join("blobs"),
+        }
+    }
+
+    /// Store the `worlds` directories in `server_path` as the snapshot `id`. Returns the size of the
+    /// files in it.
+    pub fn snapshot(&self, id: &str, server_path: &Path, worlds: &[String]) -> io::Result<u64> {
+        let mut manifest = Manifest { size: 0, directories: Vec::new(), files: Vec::new() };
+
+        for world in worlds {
+            self.add_directory(&mut manifest, server_path, Path::new(world))?;
+        }
+
+        fs::create_dir_all(&self.snapshots)?;
+
+        // the snapshot only exists once its manifest is complete
+        let partial = self.snapshots.join(format!("{id}.json.{PARTIAL_EXTENSION}"));
+        let written = json::to_string(&manifest).map_err(io::Error::other)
+            .and_then(|manifest| fs::write(&partial, manifest));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+        fs::rename(&partial, self.manifest_path(id))?;
+
+        Ok(manifest.size)
+    }
+
+    /// The id and size of every snapshot
+    pub fn snapshots(&self) -> io::Result<Vec<(String, u64)>> {
+        let entries = match fs::read_dir(&self.snapshots) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut snapshots = Vec::new();
+
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+
+            if let Some(id) = name.strip_suffix(".json") {
+                snapshots.push((id.to_string(), self.manifest(id)?.size));
+            }
+        }
+
+        Ok(snapshots)
+    }
+
+    /// Remove a snapshot, leaving its blobs for garbage collection
+    pub fn remove(&self, id: &str) -> io::Result<()> {
+        fs::remove_file(self.manifest_path(id))
+    }
+
+    /// Recreate the snapshot `id` in `destination`, which must not exist yet
+    pub fn unpack(&self, id: &str, destination: &Path) -> io::Result<()> {
+        let manifest = self.manifest(id)?;
+
+        fs::create_dir_all(destination)?;
+
+        for directory in &manifest.directories {
+            fs::create_dir_all(destination.join(relative_path(directory)?))?;
+        }
+
+        for file in &manifest.files {
+            let mut out = File::create(destination.join(relative_path(&file.path)?))?;
+
+            if file.chunked {
+                for hash in self.recipe(&file.blob)? {
+                    out.write_all(&self.read_blob(&hash)?)?;
+                }
+            } else {
+                out.write_all(&self.read_blob(&file.blob)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove the blobs that no snapshot refers to, and the blobs and manifests that were never
+    /// finished. Nothing is removed if any snapshot can't be read, since its blobs can't be told
+    /// apart from garbage.
+    pub fn collect_garbage(&self) -> io::Result<Garbage> {
+        let mut referenced = HashSet::new();
+
+        for (id, _) in self.snapshots()? {
+            for file in self.manifest(&id)?.files {
+                // recipes are shared between snapshots, so each is only read once
+                if file.chunked && !referenced.contains(&file.blob) {
+                    referenced.extend(self.recipe(&file.blob)?);
+                }
+                referenced.insert(file.blob);
+            }
+        }
+
+        let mut garbage = Garbage { blobs: 0, bytes: 0, partials: 0 };
+        let now = SystemTime::now();
+
+        // a snapshot that failed while its manifest was being written leaves it behind
+        if let Ok(entries) = fs::read_dir(&self.snapshots) {
+            for entry in entries {
+                let entry = entry?;
+
+                if is_partial(&entry.path()) && is_stale(&entry.metadata()?, now) {
+                    fs::remove_file(entry.path())?;
+                    garbage.partials += 1;
+                }
+            }
+        }
+
+        let fanout = match fs::read_dir(&self.blobs) {
+            Ok(fanout) => fanout,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(garbage),
+            Err(e) => return Err(e),
+        };
+
+        for directory in fanout {
+            for entry in fs::read_dir(directory?.path())? {
+                let entry = entry?;
+                let hash = entry.file_name().to_string_lossy().into_owned();
+                let metadata = entry.metadata()?;
+
+                if referenced.contains(&hash) || !is_stale(&metadata, now) {
+                    continue;
+                }
+
+                fs::remove_file(entry.path())?;
+
+                if is_partial(&entry.path()) {
+                    garbage.partials += 1;
+                } else {
+                    garbage.blobs += 1;
+                    garbage.bytes += metadata.len();
+                }
+            }
+        }
+
+        Ok(garbage)
+    }
+
+    fn add_directory(&self, manifest: &mut Manifest, server_path: &Path, relative: &Path) -> io::Result<()> {
+        manifest.directories.push(slash_path(relative));
+
+        for entry in fs::read_dir(server_path.join(relative))? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let relative = relative.join(entry.file_name());
+
+            if file_type.is_dir() {
+                self.add_directory(manifest, server_path, &relative)?;
+                continue;
+            }
+            // like Minecraft, worlds don't have links in them
+            if !file_type.is_file() {
+                continue;
+            }
+
+            let data = fs::read(server_path.join(&relative))?;
+            let chunked = relative.extension().is_some_and(|e| e == "mca");
+
+            let blob = if chunked {
+                let mut recipe = String::new();
+
+                for segment in region_segments(&data) {
+                    recipe.push_str(&self.write_blob(&data[segment])?);
+                    recipe.push('\n');
+                }
+
+                self.write_blob(recipe.as_bytes())?
+            } else {
+                self.write_blob(&data)?
+            };
+
+            manifest.size += data.len() as u64;
+            manifest.files.push(ManifestFile { path: slash_path(&relative), blob, chunked });
+        }
+
+        Ok(())
+    }
+
+    /// Store `data` unless it already is, and return its hash
+    fn write_blob(&self, data: &[u8]) -> io::Result<String> {
+        let hash = hex::encode(Sha256::digest(data));
+        let path = self.blob_path(&hash);
+
+        if path.exists() {
+            // used again, so it's not garbage even if the snapshots that used it are gone
+            File::options().append(true).open(&path)?.set_modified(SystemTime::now())?;
+            return Ok(hash);
+        }
+
+        fs::create_dir_all(path.parent().unwrap_or(&self.blobs))?;
+
+        let partial = path.with_extension(PARTIAL_EXTENSION);
+        fs::write(&partial, data)?;
+        fs::rename(&partial, &path)?;
+
+        Ok(hash)
+    }
+
+    fn read_blob(&self, hash: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.blob_path(hash))
+    }
+
+    fn recipe(&self, hash: &str) -> io::Result<Vec<String>> {
+        let recipe = String::from_utf8(self.read_blob(hash)?).map_err(io::Error::other)?;
+
+        Ok(recipe.lines().map(str::to_string).collect())
+    }
+
+    fn manifest(&self, id: &str) -> io::Result<Manifest> {
+        json::from_slice(&fs::read(self.manifest_path(id))?).map_err(io::Error::other)
+    }
+
+    fn manifest_path(&self, id: &str) -> PathBuf {
+        self.snapshots.join(format!("{id}.json"))
+    }
+
+    /// Blobs are spread over directories named after the first two digits of their hash
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.blobs.join(hash.get(..2).unwrap_or("00")).join(hash)
+    }
+}
+
+/// Split a region file into its header, each of its chunks, and whatever lies between them.
+/// Anything that isn't laid out like a region file is kept in one piece.
+fn region_segments(data: &[u8]) -> Vec<Range<usize>> {
+    if data.len() < REGION_HEADER {
+        return std::iter::once(0..data.len()).collect();
+    }
+
+    let mut bounds = vec![0, REGION_HEADER, data.len()];
+
+    // each location is 3 bytes of offset and 1 byte of length, both in sectors
+    for location in data[..SECTOR].chunks_exact(4) {
+        let offset = u32::from_be_bytes([0, location[0], location[1], location[2]]) as usize * SECTOR;
+        let length = location[3] as usize * SECTOR;
+
+        if offset >= REGION_HEADER && length > 0 {
+            bounds.push(offset.min(data.len()));
+            bounds.push((offset + length).min(data.len()));
+        }
+    }
+
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    bounds.windows(2).map(|pair| pair[0]..pair[1]).collect()
+}
+
+/// Whether a file is a blob or manifest that's still being written, or never was completely
+fn is_partial(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == PARTIAL_EXTENSION)
+}
+
+/// Whether a file was last written or used longer than GC_GRACE ago
+fn is_stale(metadata: &fs::Metadata, now: SystemTime) -> bool {
+    metadata.modified().ok()
+        .and_then(|modified| now.duration_since(modified).ok())
+        .is_some_and(|age| age >= GC_GRACE)
+}
+
+/// Paths in manifests always use `/`
+fn slash_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// A path from a manifest, which mustn't lead outside of where it's unpacked
+fn relative_path(path: &str) -> io::Result<PathBuf> {
+    let path = PathBuf::from(path);
+
+    if path.components().all(|c| matches!(c, Component::Normal(_))) {
+        Ok(path)
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid path in manifest: {}", path.display())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own in the system's temporary directory, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("chunk-store-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A region file with two chunks, filled with `first` and `second`
+    fn region(first: u8, second: u8) -> Vec<u8> {
+        let mut data = vec![0; REGION_HEADER];
+        // chunk 0 in sector 2 and chunk 1 in sector 3, one sector each
+        data[..4].copy_from_slice(&[0, 0, 2, 1]);
+        data[4..8].copy_from_slice(&[0, 0, 3, 1]);
+        data.extend(std::iter::repeat_n(first, SECTOR));
+        data.extend(std::iter::repeat_n(second, SECTOR));
+        data
+    }
+
+    fn write_world(server: &Path, region_data: &[u8]) {
+        fs::create_dir_all(server.join("world/region")).unwrap();
+        fs::create_dir_all(server.join("world/data")).unwrap();
+        fs::write(server.join("world/level.dat"), b"level").unwrap();
+        fs::write(server.join("world/region/r.0.0.mca"), region_data).unwrap();
+    }
+
+    fn blob_count(store: &ChunkStore) -> usize {
+        fs::read_dir(&store.blobs).unwrap()
+            .map(|directory| fs::read_dir(directory.unwrap().path()).unwrap().count())
+            .sum()
+    }
+
+    fn age(path: &Path) {
+        let old = SystemTime::now() - GC_GRACE - Duration::from_secs(60);
+        File::options().append(true).open(path).unwrap().set_modified(old).unwrap();
+    }
+
+    #[test]
+    fn unpacks_what_was_snapshotted() {
+        let temp = TempDir::new("round-trip");
+        let server = temp.0.join("server");
+        let store = ChunkStore::new(&temp.0.join("backups"));
+        write_world(&server, &region(1, 2));
+
+        let size = store.snapshot("a", &server, &["world".to_string()]).unwrap();
+        assert_eq!(size, 5 + region(1, 2).len() as u64);
+
+        let restored = temp.0.join("restored");
+        store.unpack("a", &restored).unwrap();
+
+        assert_eq!(fs::read(restored.join("world/level.dat")).unwrap(), b"level");
+        assert_eq!(fs::read(restored.join("world/region/r.0.0.mca")).unwrap(), region(1, 2));
+        assert!(restored.join("world/data").is_dir());
+    }
+
+    #[test]
+    fn stores_unchanged_chunks_once() {
+        let temp = TempDir::new("dedup");
+        let server = temp.0.join("server");
+        let store = ChunkStore::new(&temp.0.join("backups"));
+        let worlds = ["world".to_string()];
+
+        write_world(&server, &region(1, 2));
+        store.snapshot("a", &server, &worlds).unwrap();
+        // level.dat, the recipe, the header and two chunks
+        assert_eq!(blob_count(&store), 5);
+
+        store.snapshot("b", &server, &worlds).unwrap();
+        assert_eq!(blob_count(&store), 5);
+
+        // only the chunk that changed and the new recipe are stored
+        write_world(&server, &region(1, 3));
+        store.snapshot("c", &server, &worlds).unwrap();
+        assert_eq!(blob_count(&store), 7);
+
+        let restored = temp.0.join("restored");
+        store.unpack("a", &restored).unwrap();
+        assert_eq!(fs::read(restored.join("world/region/r.0.0.mca")).unwrap(), region(1, 2));
+    }
+
+    #[test]
+    fn collects_stale_partial_files() {
+        let temp = TempDir::new("partials");
+        let server = temp.0.join("server");
+        let store = ChunkStore::new(&temp.0.join("backups"));
+        write_world(&server, &region(1, 2));
+        store.snapshot("a", &server, &["world".to_string()]).unwrap();
+
+        let stale_blob = store.blobs.join("00").join(format!("00ff.{PARTIAL_EXTENSION}"));
+        let stale_manifest = store.snapshots.join(format!("b.json.{PARTIAL_EXTENSION}"));
+        let fresh_manifest = store.snapshots.join(format!("c.json.{PARTIAL_EXTENSION}"));
+        fs::create_dir_all(stale_blob.parent().unwrap()).unwrap();
+        for path in [&stale_blob, &stale_manifest, &fresh_manifest] {
+            fs::write(path, b"partial").unwrap();
+        }
+        age(&stale_blob);
+        age(&stale_manifest);
+
+        let garbage = store.collect_garbage().unwrap();
+
+        assert_eq!((garbage.blobs, garbage.partials), (0, 2));
+        assert!(!stale_blob.exists() && !stale_manifest.exists());
+        // it may still be being written
+        assert!(fresh_manifest.exists());
+        assert_eq!(store.snapshots().unwrap().len(), 1);
+    }
+}
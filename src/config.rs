@@ -0,0 +1,692 @@
+// Configuration
+//
+// Settings are layered, each layer overriding the one before it:
+// 1. Defaults
+// 2. The TOML config file (config.toml, or the path given with --config)
+// 3. Environment variables (which may come from .env)
+//
+// Minecraft servers are configured as profiles in `[servers.<id>]` tables. The `[minecraft]`,
+// `[restart]`, `[backup]`, `[hooks]` and `[[schedule]]` tables (and their environment variables)
+// describe a profile named "default", which is how a single server was configured before there
+// could be several.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use rocket::serde::{Deserialize, Deserializer};
+use thiserror::Error;
+
+use crate::auth::Role;
+use crate::schedule::Window;
+
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Name of the profile described by the `[minecraft]` table
+pub const DEFAULT_SERVER_ID: &str = "default";
+
+#[derive(Clone)]
+pub struct Settings {
+    pub address_hint: String,
+    pub webserver_port: u16,
+    pub start_client_interval: u64,
+    pub start_global_interval: u64,
+    pub start_cooldown: u64,
+    pub users_file: String,
+    pub anonymous_role: Option<Role>,
+    pub cors_origin: Option<String>,
+    /// Reverse proxies whose X-Forwarded-For header is believed
+    pub trusted_proxies: Vec<IpAddr>,
+    /// SQLite database that server sessions, events and player counts are recorded in, if any
+    pub history_database: Option<String>,
+    /// Seconds between player count samples
+    pub history_sample_interval: u64,
+    /// The `[minecraft]` profile first, then the `[servers.<id>]` profiles sorted by id
+    pub servers: Vec<ServerProfile>,
+}
+
+/// Everything needed to run one Minecraft server
+#[derive(Clone)]
+pub struct ServerProfile {
+    pub id: String,
+    pub server_path: String,
+    pub run_command: String,
+    pub rcon_password: String,
+    pub minecraft_port: u16,
+    pub rcon_port: u16,
+    pub minecraft_idle_timeout: u64,
+    pub minecraft_stop_timeout: u64,
+    pub minecraft_term_timeout: u64,
+    pub restart_policy: RestartPolicy,
+    pub restart_max_count: usize,
+    pub restart_window: u64,
+    pub restart_backoff: u64,
+    pub restart_backoff_max: u64,
+    pub console_buffer_lines: usize,
+    /// How many events /events can replay to clients that reconnect
+    pub event_history: usize,
+    /// Listen on the Minecraft port while it's offline and start it when somebody joins
+    pub wake_on_join: bool,
+    pub wake_motd: String,
+    /// How long players are told to wait before joining again
+    pub wake_retry_after: u64,
+    /// Answer server list pings while Minecraft is offline
+    pub offline_status: bool,
+    pub offline_motd: String,
+    pub starting_motd: String,
+    pub crashed_motd: String,
+    /// Where Minecraft answers the Query protocol (enable-query in server.properties), if it does
+    pub query_port: Option<u16>,
+    /// If set, the application owns `minecraft_port` and forwards players to Minecraft on this port
+    pub backend_port: Option<u16>,
+    /// Send a PROXY protocol v2 header to Minecraft so it sees players' real addresses
+    pub proxy_protocol: bool,
+    /// When Minecraft is kept on or off
+    pub schedule: Vec<Window>,
+    /// Seconds before a stop at which players are warned, longest first
+    pub stop_warnings: Vec<u64>,
+    /// Warn players with a title as well as in chat
+    pub stop_warning_title: bool,
+    /// Where backups are kept, if the world is backed up at all
+    pub backup_directory: Option<PathBuf>,
+    /// Back the world up whenever Minecraft stops
+    pub backup_after_stop: bool,
+    /// Seconds between backups while Minecraft is running, if it's backed up while running
+    pub backup_interval: Option<u64>,
+    /// How many of the most recent backups are kept
+    pub backup_keep_last: usize,
+    /// For how many days the last backup of each day is kept
+    pub backup_keep_daily: u64,
+    /// World directories in `server_path` to back up, if not the ones named by server.properties
+    pub backup_worlds: Option<Vec<String>>,
+    /// Take deduplicated snapshots rather than archives
+    pub backup_deduplicate: bool,
+    /// Shell commands run as Minecraft starts, stops and crashes
+    pub hook_pre_start: Option<String>,
+    pub hook_post_start: Option<String>,
+    pub hook_pre_stop: Option<String>,
+    pub hook_post_stop: Option<String>,
+    pub hook_on_crash: Option<String>,
+    /// Seconds a hook may run for before it's killed
+    pub hook_timeout: u64,
+}
+
+const DEFAULT_RUN_COMMAND: &str = "run.sh";
+const DEFAULT_ADDRESS_HINT: &str = "127.0.0.1";
+const DEFAULT_WEBSERVER_PORT: u16 = 3000;
+const DEFAULT_MINECRAFT_PORT: u16 = 25565;
+const DEFAULT_RCON_PORT: u16 = 25575;
+const DEFAULT_IDLE_TIMEOUT: u64 = 120;
+const DEFAULT_STOP_TIMEOUT: u64 = 60;
+const DEFAULT_TERM_TIMEOUT: u64 = 10;
+const DEFAULT_RESTART_MAX_COUNT: usize = 3;
+const DEFAULT_RESTART_WINDOW: u64 = 600;
+const DEFAULT_RESTART_BACKOFF: u64 = 10;
+const DEFAULT_RESTART_BACKOFF_MAX: u64 = 300;
+const DEFAULT_CONSOLE_BUFFER_LINES: usize = 1000;
+const DEFAULT_EVENT_HISTORY: usize = 100;
+const DEFAULT_BACKUP_KEEP_LAST: usize = 5;
+const DEFAULT_BACKUP_KEEP_DAILY: u64 = 7;
+const DEFAULT_HOOK_TIMEOUT: u64 = 60;
+const DEFAULT_STOP_WARNINGS: [u64; 3] = [60, 30, 10];
+const DEFAULT_WAKE_MOTD: &str = "Sleeping, join to wake the server up";
+const DEFAULT_WAKE_RETRY_AFTER: u64 = 30;
+const DEFAULT_STARTING_MOTD: &str = "Starting…";
+const DEFAULT_CRASHED_MOTD: &str = "Crashed";
+const DEFAULT_START_CLIENT_INTERVAL: u64 = 60;
+const DEFAULT_START_GLOBAL_INTERVAL: u64 = 10;
+const DEFAULT_START_COOLDOWN: u64 = 30;
+const DEFAULT_USERS_FILE: &str = "users.txt";
+const DEFAULT_HISTORY_SAMPLE_INTERVAL: u64 = 60;
+
+/// When the control thread should start Minecraft again by itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Only start Minecraft when asked to
+    Never,
+    /// Restart Minecraft if it crashes
+    OnCrash,
+    /// Restart Minecraft whenever it exits, unless it was stopped by the webserver or for idling
+    Always,
+}
+
+impl FromStr for RestartPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(RestartPolicy::Never),
+            "on-crash" => Ok(RestartPolicy::OnCrash),
+            "always" => Ok(RestartPolicy::Always),
+            _ => Err("expected one of never, on-crash or always"),
+        }
+    }
+}
+
+/// Seconds before a stop at which players are warned. In the config file this is an array, and in
+/// the environment a comma separated list.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", transparent)]
+struct StopWarnings(Vec<u64>);
+
+impl FromStr for StopWarnings {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|w| !w.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(StopWarnings)
+    }
+}
+
+/// World directories to back up. In the config file this is an array, and in the environment a
+/// comma separated list.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", transparent)]
+struct BackupWorlds(Vec<String>);
+
+impl FromStr for BackupWorlds {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(BackupWorlds(s.split(',')
+            .map(str::trim)
+            .filter(|w| !w.is_empty())
+            .map(String::from)
+            .collect()))
+    }
+}
+
+/// Addresses of reverse proxies. In the config file this is an array, and in the environment a
+/// comma separated list.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", transparent)]
+struct TrustedProxies(Vec<IpAddr>);
+
+impl FromStr for TrustedProxies {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|a| !a.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(TrustedProxies)
+    }
+}
+
+/// What visitors who aren't logged in may do, if anything
+#[derive(Debug, Clone, Copy)]
+struct AnonymousRole(Option<Role>);
+
+impl FromStr for AnonymousRole {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(AnonymousRole(None)),
+            role => role.parse().map(|r| AnonymousRole(Some(r)))
+                .map_err(|_| "expected one of none, viewer, starter or admin"),
+        }
+    }
+}
+
+/// The config file as written. Everything is optional so that the other layers can fill in the
+/// gaps.
+#[derive(Deserialize, Default)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+struct ConfigFile {
+    web: WebSection,
+    minecraft: MinecraftSection,
+    restart: RestartSection,
+    backup: BackupSection,
+    hooks: HooksSection,
+    schedule: Option<Vec<Window>>,
+    rate_limit: RateLimitSection,
+    history: HistorySection,
+    servers: BTreeMap<String, MinecraftSection>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+struct WebSection {
+    port: Option<u16>,
+    address_hint: Option<String>,
+    users_file: Option<String>,
+    #[serde(deserialize_with = "from_str")]
+    anonymous_role: Option<AnonymousRole>,
+    cors_origin: Option<String>,
+    trusted_proxies: Option<TrustedProxies>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+struct MinecraftSection {
+    server_path: Option<String>,
+    run_command: Option<String>,
+    port: Option<u16>,
+    rcon_port: Option<u16>,
+    rcon_password: Option<String>,
+    idle_timeout: Option<u64>,
+    stop_timeout: Option<u64>,
+    term_timeout: Option<u64>,
+    console_buffer_lines: Option<usize>,
+    event_history: Option<usize>,
+    wake_on_join: Option<bool>,
+    wake_motd: Option<String>,
+    wake_retry_after: Option<u64>,
+    offline_status: Option<bool>,
+    offline_motd: Option<String>,
+    starting_motd: Option<String>,
+    crashed_motd: Option<String>,
+    query_port: Option<u16>,
+    backend_port: Option<u16>,
+    proxy_protocol: Option<bool>,
+    stop_warnings: Option<StopWarnings>,
+    stop_warning_title: Option<bool>,
+    /// The default profile also takes these from the top level `[restart]` table
+    restart: RestartSection,
+    /// The default profile also takes these from the top level `[backup]` table
+    backup: BackupSection,
+    /// The default profile also takes these from the top level `[hooks]` table
+    hooks: HooksSection,
+    /// The default profile also takes these from the top level `[[schedule]]` tables
+    schedule: Option<Vec<Window>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+struct RestartSection {
+    #[serde(deserialize_with = "from_str")]
+    policy: Option<RestartPolicy>,
+    max_count: Option<usize>,
+    window: Option<u64>,
+    backoff: Option<u64>,
+    backoff_max: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+struct BackupSection {
+    directory: Option<String>,
+    after_stop: Option<bool>,
+    interval: Option<u64>,
+    keep_last: Option<usize>,
+    keep_daily: Option<u64>,
+    worlds: Option<BackupWorlds>,
+    deduplicate: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+struct HooksSection {
+    pre_start: Option<String>,
+    post_start: Option<String>,
+    pre_stop: Option<String>,
+    post_stop: Option<String>,
+    on_crash: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+struct RateLimitSection {
+    client_interval: Option<u64>,
+    global_interval: Option<u64>,
+    cooldown: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(crate = "rocket::serde", default, deny_unknown_fields)]
+struct HistorySection {
+    database: Option<String>,
+    sample_interval: Option<u64>,
+}
+
+/// Deserialize a string through its `FromStr` implementation, so that the config file and
+/// environment variables accept the same spellings
+fn from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where D: Deserializer<'de>,
+      T: FromStr,
+      T::Err: Display {
+    let s = String::deserialize(deserializer)?;
+
+    s.parse().map(Some).map_err(rocket::serde::de::Error::custom)
+}
+
+/// Describes the ways in which the configuration can be invalid
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Unable to read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    // toml's message points at the line and key
+    #[error("Invalid configuration in {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("The {var} environment variable is not a valid value for {key}")]
+    EnvValue { var: &'static str, key: &'static str },
+    #[error("Missing {0}")]
+    Missing(String),
+    #[error("{0}.backend_port must be different from {0}.port")]
+    BackendPort(String),
+    #[error("No Minecraft servers are configured: set minecraft.server_path (SERVER_PATH) or add a [servers.<id>] table")]
+    NoServers,
+    #[error("Invalid server id `{0}`: ids may only contain letters, numbers, - and _")]
+    ServerId(String),
+    #[error("Server `{0}` is configured twice (the [minecraft] table is named \"default\")")]
+    DuplicateServer(String),
+    #[error("Servers `{0}` and `{1}` both use port {2}")]
+    PortClash(String, String, u16),
+    #[error("Server `{0}` uses port {1} for more than one thing")]
+    OwnPortClash(String, u16),
+}
+
+/// Load the settings. `path` is the config file given on the command line, which must exist; if
+/// there is none, config.toml is used if it exists.
+pub fn load(path: Option<&Path>) -> Result<Settings, ConfigError> {
+    use ConfigError::*;
+
+    let mut file = match path {
+        Some(path) => read_file(path)?,
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => read_file(Path::new(DEFAULT_CONFIG_PATH))?,
+        None => ConfigFile::default(),
+    };
+
+    // the top level [restart], [backup] and [hooks] tables belong to the default profile
+    file.minecraft.restart = std::mem::take(&mut file.minecraft.restart).or(std::mem::take(&mut file.restart));
+    file.minecraft.backup = std::mem::take(&mut file.minecraft.backup).or(std::mem::take(&mut file.backup));
+    file.minecraft.hooks = std::mem::take(&mut file.minecraft.hooks).or(std::mem::take(&mut file.hooks));
+    file.minecraft.schedule = file.minecraft.schedule.take().or(file.schedule.take());
+
+    apply_env(&mut file)?;
+
+    let ConfigFile { web, minecraft, rate_limit, history, servers, .. } = file;
+
+    let address_hint = web.address_hint.unwrap_or_else(|| DEFAULT_ADDRESS_HINT.to_string());
+    let webserver_port = web.port.unwrap_or(DEFAULT_WEBSERVER_PORT);
+
+    // where players are sent to start a server that's offline
+    let website = match webserver_port {
+        80 => format!("http://{address_hint}"),
+        port => format!("http://{address_hint}:{port}"),
+    };
+
+    let mut profiles = Vec::new();
+
+    if minecraft.server_path.is_some() {
+        profiles.push(ServerProfile::new(DEFAULT_SERVER_ID, minecraft, "minecraft", &website)?);
+    }
+
+    for (id, section) in servers {
+        if !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(ServerId(id));
+        }
+        if profiles.iter().any(|p| p.id == id) {
+            return Err(DuplicateServer(id));
+        }
+
+        let table = format!("servers.{id}");
+        profiles.push(ServerProfile::new(&id, section, &table, &website)?);
+    }
+
+    if profiles.is_empty() {
+        return Err(NoServers);
+    }
+
+    check_ports(&profiles)?;
+
+    Ok(Settings {
+        address_hint,
+        webserver_port,
+        start_client_interval: rate_limit.client_interval.unwrap_or(DEFAULT_START_CLIENT_INTERVAL),
+        start_global_interval: rate_limit.global_interval.unwrap_or(DEFAULT_START_GLOBAL_INTERVAL),
+        start_cooldown: rate_limit.cooldown.unwrap_or(DEFAULT_START_COOLDOWN),
+        users_file: web.users_file.unwrap_or_else(|| DEFAULT_USERS_FILE.to_string()),
+        anonymous_role: web.anonymous_role.map_or(Some(Role::Viewer), |r| r.0),
+        cors_origin: web.cors_origin.filter(|o| !o.is_empty()),
+        trusted_proxies: web.trusted_proxies.map_or_else(Vec::new, |p| p.0),
+        // an empty path turns the history off, as does leaving it out
+        history_database: history.database.filter(|d| !d.is_empty()),
+        history_sample_interval: history.sample_interval.unwrap_or(DEFAULT_HISTORY_SAMPLE_INTERVAL).max(1),
+        servers: profiles,
+    })
+}
+
+impl ServerProfile {
+    /// Fill in the defaults for the profile from the table named `table`. `website` is the web
+    /// interface's address, for the default offline MOTD.
+    fn new(id: &str, section: MinecraftSection, table: &str, website: &str) -> Result<Self, ConfigError> {
+        let MinecraftSection { restart, backup, hooks, .. } = section;
+
+        // an empty command (as in .env.example) is no hook at all
+        let hook = |command: Option<String>| command.filter(|c| !c.trim().is_empty());
+
+        // only the default profile can be configured from the environment
+        let missing = |key: &str, var: &str| ConfigError::Missing(match id {
+            DEFAULT_SERVER_ID => format!("{table}.{key} (or the {var} environment variable)"),
+            _ => format!("{table}.{key}"),
+        });
+
+        let minecraft_port = section.port.unwrap_or(DEFAULT_MINECRAFT_PORT);
+
+        if section.backend_port == Some(minecraft_port) {
+            return Err(ConfigError::BackendPort(table.to_string()));
+        }
+
+        Ok(ServerProfile {
+            id: id.to_string(),
+            server_path: section.server_path
+                .ok_or_else(|| missing("server_path", "SERVER_PATH"))?,
+            run_command: section.run_command.unwrap_or_else(|| DEFAULT_RUN_COMMAND.to_string()),
+            rcon_password: section.rcon_password
+                .ok_or_else(|| missing("rcon_password", "RCON_PASSWORD"))?,
+            minecraft_port,
+            rcon_port: section.rcon_port.unwrap_or(DEFAULT_RCON_PORT),
+            minecraft_idle_timeout: section.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT),
+            minecraft_stop_timeout: section.stop_timeout.unwrap_or(DEFAULT_STOP_TIMEOUT),
+            minecraft_term_timeout: section.term_timeout.unwrap_or(DEFAULT_TERM_TIMEOUT),
+            restart_policy: restart.policy.unwrap_or(RestartPolicy::Never),
+            restart_max_count: restart.max_count.unwrap_or(DEFAULT_RESTART_MAX_COUNT),
+            restart_window: restart.window.unwrap_or(DEFAULT_RESTART_WINDOW),
+            restart_backoff: restart.backoff.unwrap_or(DEFAULT_RESTART_BACKOFF),
+            restart_backoff_max: restart.backoff_max.unwrap_or(DEFAULT_RESTART_BACKOFF_MAX),
+            console_buffer_lines: section.console_buffer_lines.unwrap_or(DEFAULT_CONSOLE_BUFFER_LINES),
+            event_history: section.event_history.unwrap_or(DEFAULT_EVENT_HISTORY),
+            wake_on_join: section.wake_on_join.unwrap_or(false),
+            wake_motd: section.wake_motd.unwrap_or_else(|| DEFAULT_WAKE_MOTD.to_string()),
+            wake_retry_after: section.wake_retry_after.unwrap_or(DEFAULT_WAKE_RETRY_AFTER),
+            offline_status: section.offline_status.unwrap_or(false),
+            offline_motd: section.offline_motd.unwrap_or_else(|| format!("Offline, visit {website} to start the server")),
+            starting_motd: section.starting_motd.unwrap_or_else(|| DEFAULT_STARTING_MOTD.to_string()),
+            crashed_motd: section.crashed_motd.unwrap_or_else(|| DEFAULT_CRASHED_MOTD.to_string()),
+            query_port: section.query_port,
+            backend_port: section.backend_port,
+            proxy_protocol: section.proxy_protocol.unwrap_or(false),
+            schedule: section.schedule.unwrap_or_default(),
+            stop_warnings: section.stop_warnings.map_or(DEFAULT_STOP_WARNINGS.to_vec(), |w| w.0),
+            stop_warning_title: section.stop_warning_title.unwrap_or(true),
+            // an empty directory turns backups off
+            backup_directory: backup.directory.filter(|d| !d.is_empty()).map(PathBuf::from),
+            backup_after_stop: backup.after_stop.unwrap_or(true),
+            backup_interval: backup.interval.filter(|i| *i > 0),
+            backup_keep_last: backup.keep_last.unwrap_or(DEFAULT_BACKUP_KEEP_LAST),
+            backup_keep_daily: backup.keep_daily.unwrap_or(DEFAULT_BACKUP_KEEP_DAILY),
+            // no worlds (e.g. an empty BACKUP_WORLDS) means the ones server.properties names
+            backup_worlds: backup.worlds.map(|w| w.0).filter(|w| !w.is_empty()),
+            backup_deduplicate: backup.deduplicate.unwrap_or(false),
+            hook_pre_start: hook(hooks.pre_start),
+            hook_post_start: hook(hooks.post_start),
+            hook_pre_stop: hook(hooks.pre_stop),
+            hook_post_stop: hook(hooks.post_stop),
+            hook_on_crash: hook(hooks.on_crash),
+            hook_timeout: hooks.timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT),
+        })
+    }
+}
+
+impl ServerProfile {
+    /// The port Minecraft itself listens on, which is behind the proxy if there is one
+    pub fn local_port(&self) -> u16 {
+        self.backend_port.unwrap_or(self.minecraft_port)
+    }
+}
+
+impl RestartSection {
+    /// Fill in whatever this section leaves out from `other`
+    fn or(self, other: RestartSection) -> RestartSection {
+        RestartSection {
+            policy: self.policy.or(other.policy),
+            max_count: self.max_count.or(other.max_count),
+            window: self.window.or(other.window),
+            backoff: self.backoff.or(other.backoff),
+            backoff_max: self.backoff_max.or(other.backoff_max),
+        }
+    }
+}
+
+impl BackupSection {
+    /// Fill in whatever this section leaves out from `other`
+    fn or(self, other: BackupSection) -> BackupSection {
+        BackupSection {
+            directory: self.directory.or(other.directory),
+            after_stop: self.after_stop.or(other.after_stop),
+            interval: self.interval.or(other.interval),
+            keep_last: self.keep_last.or(other.keep_last),
+            keep_daily: self.keep_daily.or(other.keep_daily),
+            worlds: self.worlds.or(other.worlds),
+            deduplicate: self.deduplicate.or(other.deduplicate),
+        }
+    }
+}
+
+impl HooksSection {
+    /// Fill in whatever this section leaves out from `other`
+    fn or(self, other: HooksSection) -> HooksSection {
+        HooksSection {
+            pre_start: self.pre_start.or(other.pre_start),
+            post_start: self.post_start.or(other.post_start),
+            pre_stop: self.pre_stop.or(other.pre_stop),
+            post_stop: self.post_stop.or(other.post_stop),
+            on_crash: self.on_crash.or(other.on_crash),
+            timeout: self.timeout.or(other.timeout),
+        }
+    }
+}
+
+/// Make sure no port is used twice, by two servers or by one. This includes RCON, Query and the
+/// ports Minecraft listens on behind the proxy. Query is UDP, so it can share its number with a
+/// TCP port, as it does with Minecraft's by default.
+fn check_ports(profiles: &[ServerProfile]) -> Result<(), ConfigError> {
+    let ports = |p: &ServerProfile| {
+        [
+            Some((p.minecraft_port, "tcp")),
+            Some((p.rcon_port, "tcp")),
+            p.backend_port.map(|port| (port, "tcp")),
+            p.query_port.map(|port| (port, "udp")),
+        ].into_iter().flatten().collect::<Vec<_>>()
+    };
+
+    for (i, profile) in profiles.iter().enumerate() {
+        let own = ports(profile);
+
+        if let Some((port, _)) = own.iter().enumerate().find_map(|(j, port)| own[j + 1..].contains(port).then_some(port)) {
+            return Err(ConfigError::OwnPortClash(profile.id.clone(), *port));
+        }
+
+        for other in &profiles[i + 1..] {
+            let theirs = ports(other);
+
+            if let Some((port, _)) = own.iter().find(|port| theirs.contains(port)) {
+                return Err(ConfigError::PortClash(profile.id.clone(), other.id.clone(), *port));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn read_file(path: &Path) -> Result<ConfigFile, ConfigError> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+
+    toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+}
+
+/// Override values from the config file with environment variables. These are the same variables
+/// that configured the application before there was a config file, so they only apply to the
+/// default profile.
+fn apply_env(file: &mut ConfigFile) -> Result<(), ConfigError> {
+
+    macro_rules! var {
+        ($field:expr, $var:literal, $key:literal) => {
+            if let Ok(value) = std::env::var($var) {
+                $field = Some(value.parse().map_err(|_| ConfigError::EnvValue { var: $var, key: $key })?);
+            }
+        };
+    }
+
+    var!(file.web.port, "WEBSERVER_PORT", "web.port");
+    var!(file.web.address_hint, "ADDRESS_HINT", "web.address_hint");
+    var!(file.web.users_file, "USERS_FILE", "web.users_file");
+    var!(file.web.anonymous_role, "ANONYMOUS_ROLE", "web.anonymous_role");
+    var!(file.web.cors_origin, "CORS_ORIGIN", "web.cors_origin");
+    var!(file.web.trusted_proxies, "TRUSTED_PROXIES", "web.trusted_proxies");
+
+    var!(file.minecraft.server_path, "SERVER_PATH", "minecraft.server_path");
+    var!(file.minecraft.run_command, "RUN_COMMAND", "minecraft.run_command");
+    var!(file.minecraft.port, "MINECRAFT_PORT", "minecraft.port");
+    var!(file.minecraft.rcon_port, "RCON_PORT", "minecraft.rcon_port");
+    var!(file.minecraft.rcon_password, "RCON_PASSWORD", "minecraft.rcon_password");
+    var!(file.minecraft.idle_timeout, "MINECRAFT_IDLE_TIMEOUT", "minecraft.idle_timeout");
+    var!(file.minecraft.stop_timeout, "MINECRAFT_STOP_TIMEOUT", "minecraft.stop_timeout");
+    var!(file.minecraft.term_timeout, "MINECRAFT_TERM_TIMEOUT", "minecraft.term_timeout");
+    var!(file.minecraft.console_buffer_lines, "CONSOLE_BUFFER_LINES", "minecraft.console_buffer_lines");
+    var!(file.minecraft.event_history, "EVENT_HISTORY", "minecraft.event_history");
+    var!(file.minecraft.wake_on_join, "WAKE_ON_JOIN", "minecraft.wake_on_join");
+    var!(file.minecraft.wake_motd, "WAKE_MOTD", "minecraft.wake_motd");
+    var!(file.minecraft.wake_retry_after, "WAKE_RETRY_AFTER", "minecraft.wake_retry_after");
+    var!(file.minecraft.offline_status, "OFFLINE_STATUS", "minecraft.offline_status");
+    var!(file.minecraft.offline_motd, "OFFLINE_MOTD", "minecraft.offline_motd");
+    var!(file.minecraft.starting_motd, "STARTING_MOTD", "minecraft.starting_motd");
+    var!(file.minecraft.crashed_motd, "CRASHED_MOTD", "minecraft.crashed_motd");
+    var!(file.minecraft.query_port, "QUERY_PORT", "minecraft.query_port");
+    var!(file.minecraft.backend_port, "BACKEND_PORT", "minecraft.backend_port");
+    var!(file.minecraft.proxy_protocol, "PROXY_PROTOCOL", "minecraft.proxy_protocol");
+    var!(file.minecraft.stop_warnings, "STOP_WARNINGS", "minecraft.stop_warnings");
+    var!(file.minecraft.stop_warning_title, "STOP_WARNING_TITLE", "minecraft.stop_warning_title");
+
+    var!(file.minecraft.restart.policy, "RESTART_POLICY", "restart.policy");
+    var!(file.minecraft.restart.max_count, "RESTART_MAX_COUNT", "restart.max_count");
+    var!(file.minecraft.restart.window, "RESTART_WINDOW", "restart.window");
+    var!(file.minecraft.restart.backoff, "RESTART_BACKOFF", "restart.backoff");
+    var!(file.minecraft.restart.backoff_max, "RESTART_BACKOFF_MAX", "restart.backoff_max");
+
+    var!(file.minecraft.backup.directory, "BACKUP_DIRECTORY", "backup.directory");
+    var!(file.minecraft.backup.after_stop, "BACKUP_AFTER_STOP", "backup.after_stop");
+    var!(file.minecraft.backup.interval, "BACKUP_INTERVAL", "backup.interval");
+    var!(file.minecraft.backup.keep_last, "BACKUP_KEEP_LAST", "backup.keep_last");
+    var!(file.minecraft.backup.keep_daily, "BACKUP_KEEP_DAILY", "backup.keep_daily");
+    var!(file.minecraft.backup.worlds, "BACKUP_WORLDS", "backup.worlds");
+    var!(file.minecraft.backup.deduplicate, "BACKUP_DEDUPLICATE", "backup.deduplicate");
+
+    var!(file.minecraft.hooks.pre_start, "HOOK_PRE_START", "hooks.pre_start");
+    var!(file.minecraft.hooks.post_start, "HOOK_POST_START", "hooks.post_start");
+    var!(file.minecraft.hooks.pre_stop, "HOOK_PRE_STOP", "hooks.pre_stop");
+    var!(file.minecraft.hooks.post_stop, "HOOK_POST_STOP", "hooks.post_stop");
+    var!(file.minecraft.hooks.on_crash, "HOOK_ON_CRASH", "hooks.on_crash");
+    var!(file.minecraft.hooks.timeout, "HOOK_TIMEOUT", "hooks.timeout");
+
+    var!(file.rate_limit.client_interval, "START_CLIENT_INTERVAL", "rate_limit.client_interval");
+    var!(file.rate_limit.global_interval, "START_GLOBAL_INTERVAL", "rate_limit.global_interval");
+    var!(file.rate_limit.cooldown, "START_COOLDOWN", "rate_limit.cooldown");
+
+    var!(file.history.database, "HISTORY_DATABASE", "history.database");
+    var!(file.history.sample_interval, "HISTORY_SAMPLE_INTERVAL", "history.sample_interval");
+
+    Ok(())
+}
@@ -0,0 +1,105 @@
+// Minecraft console capture
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use rocket::tokio::{self, io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader}};
+use rocket::tokio::process::Child;
+use rocket::tokio::sync::broadcast;
+
+/// How many new lines a slow subscriber can fall behind before it starts missing some
+const FOLLOW_CAPACITY: usize = 256;
+
+/// Longer lines are split, so that output without newlines can't take up all the memory
+const MAX_LINE_LENGTH: u64 = 16 * 1024;
+
+/// Which pipe a line of console output came from
+#[derive(Debug, Clone, Copy)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// A single line of output from Minecraft
+#[derive(Debug, Clone)]
+pub struct ConsoleLine {
+    pub stream: Stream,
+    pub text: String,
+}
+
+/// Keeps the most recent lines Minecraft printed and forwards new ones to anybody following along
+pub struct Console {
+    lines: Mutex<VecDeque<ConsoleLine>>,
+    capacity: usize,
+    follow: broadcast::Sender<ConsoleLine>,
+}
+
+impl Console {
+    pub fn new(capacity: usize) -> Arc<Self> {
+        let (follow, _) = broadcast::channel(FOLLOW_CAPACITY);
+
+        Arc::new(Console {
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            follow,
+        })
+    }
+
+    /// Take the stdout and stderr pipes of a freshly spawned Minecraft process and copy
+    /// everything printed to them into the console. The readers stop when Minecraft exits.
+    pub fn capture(self: &Arc<Self>, child: &mut Child) {
+        if let Some(stdout) = child.stdout.take() {
+            tokio::spawn(self.clone().read_lines(stdout, Stream::Stdout));
+        }
+
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(self.clone().read_lines(stderr, Stream::Stderr));
+        }
+    }
+
+    /// Get a copy of the buffered lines along with a receiver for lines printed afterward. Both
+    /// are taken together so that no line is missed or repeated in between.
+    pub fn subscribe(&self) -> (Vec<ConsoleLine>, broadcast::Receiver<ConsoleLine>) {
+        let lines = self.lines.lock().unwrap();
+
+        (lines.iter().cloned().collect(), self.follow.subscribe())
+    }
+
+    fn push(&self, line: ConsoleLine) {
+        // held while sending too, so that `subscribe` gets each line either way but not both
+        let mut lines = self.lines.lock().unwrap();
+
+        // with no room for any lines, they're only forwarded
+        if self.capacity > 0 {
+            if lines.len() >= self.capacity {
+                lines.pop_front();
+            }
+            lines.push_back(line.clone());
+        }
+
+        // nobody following along is fine
+        let _ = self.follow.send(line);
+    }
+
+    async fn read_lines(self: Arc<Self>, pipe: impl AsyncRead + Unpin, stream: Stream) {
+        let mut reader = BufReader::new(pipe);
+        let mut buf = Vec::new();
+
+        loop {
+            buf.clear();
+
+            match (&mut reader).take(MAX_LINE_LENGTH).read_until(b'\n', &mut buf).await {
+                Ok(0) => break,
+                Ok(_) => {
+                    // Minecraft (and mods) don't always print valid UTF-8
+                    let text = String::from_utf8_lossy(&buf).trim_end().to_string();
+                    self.push(ConsoleLine { stream, text });
+                },
+                Err(e) => {
+                    warn!("Stopped reading the Minecraft console: {e}");
+                    break;
+                },
+            }
+        }
+    }
+}
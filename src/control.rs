@@ -1,89 +1,680 @@
 // Control thread
 
-use std::{process::Child, time::Instant};
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant, SystemTime};
 use std::path::Path;
-use mc_query::{rcon::RconClient, status::StatusResponse};
-use rocket::tokio::{sync::{mpsc, oneshot, broadcast}, time::timeout};
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use mc_query::{query::FullStatResponse, rcon::RconClient, status::StatusResponse};
+use rocket::tokio::{self, select, sync::{mpsc, oneshot, OwnedMutexGuard}, time::{self, interval_at, timeout}};
+use rocket::tokio::process::{Child, Command};
 use thiserror::Error;
 
 use crate::attempt::{self, attempt};
-use crate::env::Env;
+use crate::backup::{self, BackupError, BackupKind, BackupReply, BackupTask, Backups, RestoreReply, RestoreTask};
+use crate::console::Console;
+use crate::countdown::{Countdown, Tick, CANCEL_COMMAND};
+use crate::metrics::Metrics;
+use crate::event_log::EventLog;
+use crate::hooks::{Hook, HookError, HookRun};
+use crate::config::{RestartPolicy, ServerProfile};
+use crate::proxy::{Proxy, ProxyLink};
+use crate::ratelimit::StartLimiter;
+use crate::roster::Roster;
+use crate::schedule::{Schedule, WindowMode};
+use crate::wake::{Join, ListedState, WakeListener, WakeRefused};
 
 const IDLE_QUERY_PERIOD_SEC: u64 = 30;
+/// How often the schedule is checked for windows opening and closing
+const SCHEDULE_CHECK_PERIOD: Duration = Duration::from_secs(20);
+/// How many more times an automatic restart tries to spawn Minecraft before it counts as a crash
+const RESTART_START_RETRIES: u32 = 2;
+/// The Query protocol is UDP, so a disabled or firewalled query port never answers
+const FULL_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 #[allow(dead_code)]
 pub enum ControlCmd {
-    StartServer,
-    StopServer,
-    Query(oneshot::Sender<Option<StatusResponse>>), // I love this.
-    LastEvent(oneshot::Sender<ControlEvent>)
+    /// Carries the name of whoever asked
+    StartServer(String),
+    StopServer(String, oneshot::Sender<StopOutcome>),
+    /// Call off a stop that players are being warned about. Answers whether there was one.
+    CancelStop(String, oneshot::Sender<bool>),
+    Query(oneshot::Sender<Option<QueryResponse>>), // I love this.
+    LastEvent(oneshot::Sender<ControlEvent>),
+    Rcon(String, oneshot::Sender<Result<String, RconCommandError>>),
+    /// Back the world up on behalf of whoever asked, answering once the backup is done
+    TakeBackup(String, BackupReply),
+    /// Put a backup (by id) in place of the world on behalf of whoever asked, answering once it's
+    /// done. Only allowed while Minecraft is offline.
+    RestoreBackup(String, String, RestoreReply),
+}
+
+/// What Minecraft says about itself
+#[derive(Debug)]
+pub struct QueryResponse {
+    pub status: StatusResponse,
+    /// The full player list and more from the Query protocol, if it's enabled
+    pub full: Option<FullStatResponse>,
+    /// How long the status ping took
+    pub latency: Duration,
+}
+
+/// Describes the ways in which running an RCON command for the webserver can fail
+#[derive(Error, Debug)]
+pub enum RconCommandError {
+    #[error("Minecraft is not running")]
+    Offline,
+    #[error("RCON command failed: {0}")]
+    Failed(String),
+}
+
+/// The control thread's answer to a stop request
+#[derive(Debug, Clone, Copy)]
+pub enum StopOutcome {
+    Stopping,
+    /// Players are being warned, and Minecraft stops once the warnings are over
+    CountingDown,
+    AlreadyOffline,
+    RestartCancelled,
+    /// Minecraft is still starting, and can only be stopped once it's up
+    Starting,
+}
+
+/// When and why the server changed state
+#[derive(Debug, Clone)]
+pub struct StateChange {
+    pub timestamp: SystemTime,
+    /// Name of the state before this one, filled in when the event is emitted
+    pub previous: Option<String>,
+    pub reason: Option<String>,
+    /// Who asked for it, if anybody did
+    pub by: Option<String>,
+}
+
+impl StateChange {
+    pub fn now() -> Self {
+        StateChange { timestamp: SystemTime::now(), previous: None, reason: None, by: None }
+    }
+
+    pub fn because(reason: impl Into<String>) -> Self {
+        StateChange { reason: Some(reason.into()), ..StateChange::now() }
+    }
+
+    pub fn by(self, name: impl Into<String>) -> Self {
+        StateChange { by: Some(name.into()), ..self }
+    }
 }
 
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
 pub enum ControlEvent {
-    Started,
-    Starting,
-    Stopped,
-    Crashed,
-    Empty,
-    Occupied
+    Started { change: StateChange },
+    Starting { change: StateChange },
+    Stopping { change: StateChange },
+    Stopped { change: StateChange },
+    /// The exit code and signal are known if Minecraft exited on its own
+    Crashed { change: StateChange, code: Option<i32>, signal: Option<i32> },
+    /// Minecraft crashed too many times in a row and won't be restarted automatically
+    CrashLoop { change: StateChange },
+    Empty { change: StateChange },
+    Occupied { change: StateChange },
+    /// The UUID is unknown if the player was only seen in a Query protocol response
+    PlayerJoined { timestamp: SystemTime, name: String, uuid: Option<String> },
+    PlayerLeft { timestamp: SystemTime, name: String, uuid: Option<String>, session_length: Duration },
+    /// A backup was put in place of the world, which was backed up first unless there wasn't one
+    Restored { timestamp: SystemTime, backup: String, safety_backup: Option<String>, by: String },
+}
+
+impl ControlEvent {
+    /// The state change this event describes, unless it's about something else (e.g. players)
+    pub fn change(&self) -> Option<&StateChange> {
+        use ControlEvent::*;
+
+        match self {
+            Started { change } | Starting { change } | Stopping { change } | Stopped { change }
+                | Crashed { change, .. } | CrashLoop { change } | Empty { change } | Occupied { change } => Some(change),
+            PlayerJoined { .. } | PlayerLeft { .. } | Restored { .. } => None,
+        }
+    }
+
+    fn change_mut(&mut self) -> Option<&mut StateChange> {
+        use ControlEvent::*;
+
+        match self {
+            Started { change } | Starting { change } | Stopping { change } | Stopped { change }
+                | Crashed { change, .. } | CrashLoop { change } | Empty { change } | Occupied { change } => Some(change),
+            PlayerJoined { .. } | PlayerLeft { .. } | Restored { .. } => None,
+        }
+    }
+
+    pub fn timestamp(&self) -> SystemTime {
+        match self {
+            ControlEvent::PlayerJoined { timestamp, .. } | ControlEvent::PlayerLeft { timestamp, .. }
+                | ControlEvent::Restored { timestamp, .. } => *timestamp,
+            // everything else is a state change
+            other => other.change().map_or_else(SystemTime::now, |c| c.timestamp),
+        }
+    }
+
+    /// A crash for which there's no exit status
+    fn crashed_because(reason: impl Into<String>) -> Self {
+        ControlEvent::Crashed { change: StateChange::because(reason), code: None, signal: None }
+    }
 }
 
-pub async fn control(mut msg: mpsc::Receiver<ControlCmd>, mut evt_sender: broadcast::Sender<ControlEvent>, settings: Env) {
+#[allow(clippy::too_many_arguments)]
+pub async fn control(
+    mut msg: mpsc::Receiver<ControlCmd>,
+    events: Arc<EventLog>,
+    profile: ServerProfile,
+    console: Arc<Console>,
+    metrics: Arc<Metrics>,
+    schedule: Arc<Schedule>,
+    backups: Option<Arc<Backups>>,
+    limiter: Arc<StartLimiter>,
+    mut proxy: Option<ProxyLink>
+) {
 
     use ControlEvent::*;
 
-    let mut last_event = Stopped;
+    let mut last_event = Stopped { change: StateChange::now() };
+    let mut restarts = Restarts::new(&profile);
+    let mut restart_at = None;
+    let mut listed = ListedState::Offline;
 
     loop {
         // Thread idle (mc server offline)
-        let (mc_server, rcon_client) = thread_idle(&mut msg, &mut evt_sender, &mut last_event, &settings).await;
+        let proxy_joins = proxy.as_mut().map(|p| &mut p.joins);
+        let (mc_server, rcon_client) = thread_idle(&mut msg, &events, &mut last_event, &profile, &console, &schedule, backups.as_ref(), &limiter, &mut restarts, restart_at, listed, proxy_joins).await;
 
-        emit_event(Started, &mut evt_sender, &mut last_event);
+        // the hook gets to know why Minecraft was started
+        let starting = last_event.change().cloned().unwrap_or_else(StateChange::now);
+        emit_event(Started { change: StateChange::now() }, &events, &mut last_event);
+
+        if let Some(hook) = HookRun::new(Hook::PostStart, &profile, &starting) {
+            hook.spawn();
+        }
 
         // Thread active (mc server online)
-        thread_active(&mut msg, &mut evt_sender, &mut last_event, &settings, mc_server, rcon_client).await;
+        let (reason, description) = thread_active(&mut msg, &events, &mut last_event, &profile, &console, &metrics, &schedule, backups.as_ref(), proxy.as_ref().map(|p| &*p.proxy), mc_server, rcon_client).await;
+
+        let crash = match &last_event {
+            Crashed { change, code, signal } => Some((change.clone(), *code, *signal)),
+            _ => None,
+        };
+        let stopped = StateChange::because(description);
+
+        emit_event(Stopped { change: stopped.clone() }, &events, &mut last_event);
+
+        if let Some(hook) = HookRun::new(Hook::PostStop, &profile, &stopped) {
+            hook.spawn();
+        }
+        if let Some((change, code, signal)) = crash {
+            if let Some(mut hook) = HookRun::new(Hook::OnCrash, &profile, &change) {
+                if let Some(code) = code {
+                    hook = hook.env("MC_EXIT_CODE", code);
+                }
+                if let Some(signal) = signal {
+                    hook = hook.env("MC_SIGNAL", signal);
+                }
+                hook.spawn();
+            }
+        }
 
-        emit_event(Stopped, &mut evt_sender, &mut last_event);
+        // a crashed world isn't worth keeping over the backups before it
+        if let Some(backups) = backups.as_ref().filter(|_| profile.backup_after_stop && reason != StopReason::Crashed) {
+            // claimed before going idle, so that starting Minecraft waits for the backup
+            match backups.try_claim() {
+                Ok(claim) => {
+                    tokio::spawn(backup::finish(profile.id.clone(), backups.create(BackupKind::Stop, claim), None));
+                },
+                Err(e) => info!("Skipping the backup of {} after it stopped: {e}", profile.id),
+            }
+        }
+
+        listed = match reason {
+            StopReason::Crashed => ListedState::Crashed,
+            _ => ListedState::Offline,
+        };
+
+        restart_at = if restarts.should_restart(reason) {
+            schedule_restart(&mut restarts, &events, &mut last_event)
+        } else {
+            None
+        };
     }
 }
 
+/// Minecraft being started in the background of `thread_idle`
+type PendingStart<'a> = Pin<Box<dyn Future<Output = Result<(Child, RconClient), StartServerError>> + Send + 'a>>;
+
 /// Patiently wait for commands from the webserver. Upon being told to start Minecraft, spawns the 
-/// process and returns a process handle and RCON client.
+/// process and returns a process handle and RCON client. Commands are still answered while it
+/// starts.
+/// 
+/// If `restart_at` is set, Minecraft is also started automatically once that instant is reached,
+/// and it's started when a scheduled window opens. With wake-on-join, players trying to join start
+/// it too, unless it's in a crash loop or `limiter` says they're too soon. Behind the proxy,
+/// `proxy_joins` says who tried to join. Otherwise the server list is told what `listed` says.
+/// Nothing starts Minecraft while the schedule keeps it off or a backup is being restored.
+#[allow(clippy::too_many_arguments)]
 async fn thread_idle(
     msg: &mut mpsc::Receiver<ControlCmd>,
-    evt_sender: &mut broadcast::Sender<ControlEvent>,
+    events: &EventLog,
     last_event: &mut ControlEvent,
-    settings: &Env
+    profile: &ServerProfile,
+    console: &Arc<Console>,
+    schedule: &Schedule,
+    backups: Option<&Arc<Backups>>,
+    limiter: &StartLimiter,
+    restarts: &mut Restarts,
+    mut restart_at: Option<Instant>,
+    mut listed: ListedState,
+    mut proxy_joins: Option<&mut mpsc::Receiver<Join>>
 ) -> (Child, RconClient) {
     
     use ControlCmd::*;
     use ControlEvent::*;
 
+    // players who tried to join while Minecraft was starting or online don't count
+    if let Some(joins) = proxy_joins.as_mut() {
+        while joins.try_recv().is_ok() {}
+    }
+
+    // the proxy owns the port if there is one
+    let mut wake = None;
+    let mut listen = (profile.wake_on_join || profile.offline_status) && proxy_joins.is_none();
+
+    let mut schedule_timer = time::interval(SCHEDULE_CHECK_PERIOD);
+    // the backup being restored, if any, and who asked for it
+    let mut restore: Option<(RestoreTask, String, RestoreReply)> = None;
+    // Minecraft being started, if it is, and whether that's an automatic restart
+    let mut starting: Option<(PendingStart, bool)> = None;
+
     loop {
-        match msg.recv().await {
-            Some(StartServer) => {
-                // send a messsage to the end-users listening on /events
-                emit_event(Starting, evt_sender, last_event);
-                
-                if let Ok((mc, rc)) = start_server(settings).await {
-                    break (mc, rc);
+        // (re)bind whenever the listener was given up for a start that failed
+        if listen && wake.is_none() && starting.is_none() {
+            match WakeListener::bind(profile, listed).await {
+                Ok(listener) => wake = Some(listener),
+                Err(e) => {
+                    error!("Unable to listen on port {} while Minecraft is offline: {e}", profile.minecraft_port);
+                    listen = false;
+                },
+            }
+        }
+
+        let restart_timer = async {
+            match restart_at {
+                Some(instant) => time::sleep_until(instant.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let restore_done = async {
+            match restore.as_mut() {
+                Some((task, ..)) => task.await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let idle = starting.is_none();
+
+        let start_done = async {
+            match starting.as_mut() {
+                Some((start, _)) => start.await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let wake_join = async {
+            match (wake.as_mut(), proxy_joins.as_mut()) {
+                (Some(listener), _) => listener.next_join().await,
+                (None, Some(joins)) => match joins.recv().await {
+                    Some(join) => join,
+                    None => std::future::pending().await,
+                },
+                (None, None) => std::future::pending().await,
+            }
+        };
+
+        select! {
+            cmd = msg.recv() => match cmd {
+                Some(StartServer(by)) if starting.is_some() => info!("{by} asked to start {}, but it's already starting", profile.id),
+                Some(StartServer(by)) => {
+                    if let Some(blocked) = start_blocked(schedule, backups) {
+                        info!("{by} asked to start {}, but {blocked}", profile.id);
+                        continue;
+                    }
+
+                    // a human is looking after the server now, so forget about previous crashes
+                    restart_at = None;
+                    restarts.reset();
+
+                    // send a messsage to the end-users listening on /events
+                    let change = StateChange::because(format!("requested by {by}")).by(by);
+                    emit_event(Starting { change: change.clone() }, events, last_event);
+
+                    // Minecraft needs the port
+                    wake = None;
+                    
+                    starting = Some((Box::pin(start_server(profile, console, backups, change)), false));
+                },
+                // stopping would have to wait for it to start, which could take a while
+                Some(StopServer(by, webserver_tx)) if starting.is_some() => {
+                    info!("{by} asked to stop {}, but it's still starting", profile.id);
+
+                    if webserver_tx.send(StopOutcome::Starting).is_err() {
+                        error!("Webserver did not get the stop outcome (receiver hung up)");
+                    }
+                },
+                Some(StopServer(by, webserver_tx)) => {
+                    let outcome = match restart_at.take() {
+                        Some(_) => {
+                            info!("{by} cancelled the pending automatic restart");
+                            StopOutcome::RestartCancelled
+                        },
+                        None => StopOutcome::AlreadyOffline,
+                    };
+
+                    if webserver_tx.send(outcome).is_err() {
+                        error!("Webserver did not get the stop outcome (receiver hung up)");
+                    }
+                },
+                // the world is only left alone until Minecraft is spawned
+                Some(TakeBackup(_, webserver_tx)) if starting.is_some() => {
+                    if webserver_tx.send(Err(BackupError::Starting)).is_err() {
+                        error!("Webserver did not get the backup (receiver hung up)");
+                    }
+                },
+                Some(RestoreBackup(_, _, webserver_tx)) if starting.is_some() => {
+                    if webserver_tx.send(Err(BackupError::Starting)).is_err() {
+                        error!("Webserver did not get the restore (receiver hung up)");
+                    }
+                },
+                Some(TakeBackup(by, webserver_tx)) => {
+                    info!("{by} asked for a backup of {}", profile.id);
+
+                    match claim_backups(backups) {
+                        Ok((backups, claim)) => {
+                            tokio::spawn(backup::finish(profile.id.clone(), backups.create(BackupKind::Manual, claim), Some(webserver_tx)));
+                        },
+                        Err(e) => {
+                            if webserver_tx.send(Err(e)).is_err() {
+                                error!("Webserver did not get the backup (receiver hung up)");
+                            }
+                        },
+                    }
+                },
+                Some(RestoreBackup(by, id, webserver_tx)) => {
+                    info!("{by} asked to restore the backup {id} of {}", profile.id);
+
+                    match claim_backups(backups) {
+                        Ok((backups, claim)) => restore = Some((backups.restore(id, claim), by, webserver_tx)),
+                        Err(e) => {
+                            if webserver_tx.send(Err(e)).is_err() {
+                                error!("Webserver did not get the restore (receiver hung up)");
+                            }
+                        },
+                    }
+                },
+                Some(CancelStop(_, webserver_tx)) => {
+                    if webserver_tx.send(false).is_err() {
+                        error!("Webserver did not get the cancel outcome (receiver hung up)");
+                    }
+                },
+                Some(LastEvent(webserver_tx)) => {
+                    if webserver_tx.send(last_event.clone()).is_err() {
+                        error!("Webserver did not get the status (receiver hung up)");
+                    }
+                },
+                // Note: if Query is sent, tx will be immediately dropped, so rx won't block the webserver
+                Some(Query(_)) => info!("Received a query, but the server wasn't online"),
+                Some(Rcon(_, webserver_tx)) => {
+                    if webserver_tx.send(Err(RconCommandError::Offline)).is_err() {
+                        error!("Webserver did not get the RCON response (receiver hung up)");
+                    }
+                },
+                None => error!("Webserver dropped sender"),
+            },
+
+            result = restore_done => {
+                let Some((_, by, webserver_tx)) = restore.take() else {
+                    continue;
+                };
+
+                let result = result.map_err(BackupError::from).and_then(|restore| restore);
+
+                match &result {
+                    Ok(restore) => broadcast_event(Restored {
+                        timestamp: SystemTime::now(),
+                        backup: restore.backup.id.clone(),
+                        safety_backup: restore.safety_backup.as_ref().map(|b| b.id.clone()),
+                        by,
+                    }, events),
+                    Err(e) => error!("Unable to restore a backup of {}: {e}", profile.id),
+                }
+
+                if webserver_tx.send(result).is_err() {
+                    error!("Webserver did not get the restore (receiver hung up)");
                 }
             },
-            Some(LastEvent(webserver_tx)) => {
-                if webserver_tx.send(last_event.clone()).is_err() {
-                    error!("Webserver did not get the status (receiver hung up)");
+
+            result = start_done => {
+                let Some((_, automatic)) = starting.take() else {
+                    continue;
+                };
+
+                match result {
+                    Ok((mc, rc)) => break (mc, rc),
+                    // A few more tries in case the failure was transient (e.g. the port was still
+                    // in use), each from the loop so that commands are still answered in between
+                    Err(e) if automatic => match restarts.retry() {
+                        Some(instant) => {
+                            warn!("Automatic restart failed, trying again in {}s: {e}", (instant - Instant::now()).as_secs());
+                            restart_at = Some(instant);
+                        },
+                        // if they all fail, count it as another crash
+                        None => {
+                            error!("Automatic restart failed: {e}");
+                            emit_event(ControlEvent::crashed_because(format!("automatic restart failed: {e}")), events, last_event);
+                            listed = ListedState::Crashed;
+
+                            restart_at = schedule_restart(restarts, events, last_event);
+                        },
+                    },
+                    Err(e) => start_failed(e, events, last_event),
                 }
             },
-            // Note: if Query is sent, tx will be immediately dropped, so rx won't block the webserver
-            Some(Query(_)) => info!("Received a query, but the server wasn't online"),
-            Some(other) => warn!("Webserver sent an invalid message: {other:?}"),
-            None => error!("Webserver dropped sender"),
+
+            Join { name, client, reply } = wake_join => {
+                // Joining is open to anybody, so unlike a start request it doesn't get the server
+                // out of a crash loop, and it's rate limited like one
+                let outcome = match &starting {
+                    Some(_) => Ok(()),
+                    None if matches!(last_event, CrashLoop { .. }) => Err(WakeRefused::CrashLoop),
+                    None => match start_blocked(schedule, backups) {
+                        Some(blocked) => Err(WakeRefused::Blocked(blocked)),
+                        None => limiter.try_start(client).map_err(WakeRefused::TooSoon),
+                    },
+                };
+                let start = outcome.is_ok() && starting.is_none();
+
+                if let Err(refused) = &outcome {
+                    info!("{name} tried to join {}, but {refused}", profile.id);
+                }
+                // the player may have given up on waiting for the answer already
+                let _ = reply.send(outcome);
+
+                if !start {
+                    continue;
+                }
+
+                info!("{name} tried to join, starting {}", profile.id);
+
+                restart_at = None;
+
+                let change = StateChange::because(format!("{name} tried to join")).by(&name);
+                emit_event(Starting { change: change.clone() }, events, last_event);
+
+                wake = None;
+
+                starting = Some((Box::pin(start_server(profile, console, backups, change)), false));
+            },
+
+            _ = restart_timer => {
+                if let Some(blocked) = start_blocked(schedule, backups) {
+                    info!("Not restarting {} because {blocked}", profile.id);
+                    restart_at = None;
+                    continue;
+                }
+
+                info!("Automatically restarting {}", profile.id);
+                let change = StateChange::because("automatic restart");
+                emit_event(Starting { change: change.clone() }, events, last_event);
+
+                wake = None;
+                restart_at = None;
+
+                starting = Some((Box::pin(start_server(profile, console, backups, change)), true));
+            },
+
+            // while Minecraft starts, windows opening and closing are left for once it's up
+            _ = schedule_timer.tick(), if idle => {
+                // only a window opening starts Minecraft, so that it can still be stopped by hand
+                // while one is open
+                let (was, mode) = schedule.advance();
+
+                if mode == Some(WindowMode::On) && was != Some(WindowMode::On) {
+                    if let Some(blocked) = start_blocked(schedule, backups) {
+                        info!("A scheduled window for {} opened, but {blocked}", profile.id);
+                        continue;
+                    }
+
+                    info!("A scheduled window opened, starting {}", profile.id);
+
+                    restart_at = None;
+                    restarts.reset();
+
+                    let change = StateChange::because("a scheduled window opened");
+                    emit_event(Starting { change: change.clone() }, events, last_event);
+
+                    wake = None;
+
+                    starting = Some((Box::pin(start_server(profile, console, backups, change)), false));
+                }
+            },
+        }
+    }
+}
+
+/// Why the active phase ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StopReason {
+    /// The webserver asked for Minecraft to stop (or went away)
+    Requested,
+    /// Nobody was online for longer than MINECRAFT_IDLE_TIMEOUT
+    Idle,
+    /// The schedule said to stop Minecraft
+    Scheduled,
+    /// Minecraft exited cleanly without being told to by us (e.g. `/stop` in-game)
+    Exited,
+    /// Minecraft exited with an error or stopped responding
+    Crashed,
+}
+
+/// Tracks automatic restarts so that a server which crashes on boot isn't restarted forever
+struct Restarts {
+    policy: RestartPolicy,
+    max_count: usize,
+    window: Duration,
+    backoff: Duration,
+    backoff_max: Duration,
+    history: VecDeque<Instant>,
+    /// How many times the pending restart has been retried
+    retries: u32,
+}
+
+impl Restarts {
+    fn new(profile: &ServerProfile) -> Self {
+        Restarts {
+            policy: profile.restart_policy,
+            max_count: profile.restart_max_count,
+            window: Duration::from_secs(profile.restart_window),
+            backoff: Duration::from_secs(profile.restart_backoff),
+            backoff_max: Duration::from_secs(profile.restart_backoff_max),
+            history: VecDeque::new(),
+            retries: 0,
+        }
+    }
+
+    fn should_restart(&self, reason: StopReason) -> bool {
+        match self.policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnCrash => reason == StopReason::Crashed,
+            RestartPolicy::Always => matches!(reason, StopReason::Crashed | StopReason::Exited),
+        }
+    }
+
+    /// Record a restart and decide when it should happen. Each restart within the window waits
+    /// twice as long as the one before it. Returns `None` once the window is full.
+    fn schedule(&mut self) -> Option<Instant> {
+        let now = Instant::now();
+
+        while self.history.front().is_some_and(|t| now - *t > self.window) {
+            self.history.pop_front();
+        }
+
+        if self.history.len() >= self.max_count {
+            return None;
+        }
+
+        let delay = attempt::backoff_delay(self.backoff, self.backoff_max, self.history.len() as u32);
+        self.history.push_back(now);
+        self.retries = 0;
+
+        Some(now + delay)
+    }
+
+    /// Decide when to try spawning Minecraft again after an automatic restart failed, backing off
+    /// like `schedule` does. Returns `None` once the restart has run out of retries.
+    fn retry(&mut self) -> Option<Instant> {
+        if self.retries >= RESTART_START_RETRIES {
+            return None;
         }
+
+        let delay = attempt::backoff_delay(self.backoff, self.backoff_max, self.retries);
+        self.retries += 1;
+
+        Some(Instant::now() + delay)
+    }
+
+    fn reset(&mut self) {
+        self.history.clear();
+        self.retries = 0;
+    }
+}
+
+/// Schedule an automatic restart, or give up and announce a crash loop
+fn schedule_restart(
+    restarts: &mut Restarts,
+    events: &EventLog,
+    last_event: &mut ControlEvent
+) -> Option<Instant> {
+    let restart_at = restarts.schedule();
+
+    match restart_at {
+        Some(instant) => info!("Restarting Minecraft in {}s", (instant - Instant::now()).as_secs()),
+        None => {
+            error!("Minecraft keeps crashing, giving up on restarting it automatically");
+
+            let reason = format!("crashed {} times within {}s", restarts.max_count, restarts.window.as_secs());
+            emit_event(ControlEvent::CrashLoop { change: StateChange::because(reason) }, events, last_event);
+        },
     }
+
+    restart_at
 }
 
 /// Await webserver commands and ping Minecraft periodically.
@@ -92,102 +683,460 @@ async fn thread_idle(
 /// - Webserver says to shut down Minecraft
 /// - Periodic pings return 0 online players for longer than MINECRAFT_IDLE_TIMEOUT
 /// - A single ping fails for whatever reason
+/// - A scheduled window closes, or the schedule says to keep Minecraft off (after warning players)
+/// 
+/// Returns as soon as the Minecraft process exits on its own, along with a description of why
+/// Minecraft stopped.
+/// 
+/// Players joining and leaving are announced as they are seen on the console or in pings. The
+/// world is backed up every BACKUP_INTERVAL seconds, and when asked, with saving turned off.
+#[allow(clippy::too_many_arguments)]
 async fn thread_active(
     msg: &mut mpsc::Receiver<ControlCmd>,
-    evt_sender: &mut broadcast::Sender<ControlEvent>,
+    events: &EventLog,
     last_event: &mut ControlEvent,
-    settings: &Env,
+    profile: &ServerProfile,
+    console: &Console,
+    metrics: &Metrics,
+    schedule: &Schedule,
+    backups: Option<&Arc<Backups>>,
+    proxy: Option<&Proxy>,
     mut mc_server: Child,
     mut rcon_client: RconClient
-) {
+) -> (StopReason, String) {
     
     use ControlCmd::*;
     use ControlEvent::*;
 
-    let idle_timeout = Duration::from_secs(settings.minecraft_idle_timeout);
+    let idle_timeout = Duration::from_secs(profile.minecraft_idle_timeout);
     let mut idle_begin = Instant::now();
     let mut is_empty = true;
+
+    let query_period = Duration::from_secs(IDLE_QUERY_PERIOD_SEC);
+    let mut query_timer = interval_at(time::Instant::now() + query_period, query_period);
+
+    let mut roster = Roster::default();
+    // lines from before Minecraft started don't matter
+    let (_, mut console_lines) = console.subscribe();
+
+    let mut schedule_timer = time::interval(SCHEDULE_CHECK_PERIOD);
+    // the pending stop, if players are being warned about one
+    let mut countdown: Option<(Countdown, StopReason)> = None;
+    let start_countdown = |reason: &str, by: Option<String>| {
+        Countdown::new(reason, by, &profile.stop_warnings, profile.stop_warning_title)
+    };
+
+    let mut backup_timer = backups.and(profile.backup_interval).map(|secs| {
+        let period = Duration::from_secs(secs);
+        interval_at(time::Instant::now() + period, period)
+    });
+    // the backup being taken, if any, and who asked for it
+    let mut live_backup: Option<(BackupTask, Option<BackupReply>)> = None;
     
-    loop {
-        // Check for messages from the webserver
-        match timeout(Duration::from_secs(IDLE_QUERY_PERIOD_SEC), msg.recv()).await {
-            Ok(Some(StopServer)) => {
-                try_stop_server(&mut mc_server, &mut rcon_client).await;
-                break;
-            },
-            Ok(Some(Query(webserver_tx))) => {
-                // query minecraft server and tell webserver result
-                let response = mc_query::status("localhost", settings.minecraft_port).await
-                    .ok();
+    let reason = loop {
+        let countdown_timer = {
+            let next_at = countdown.as_ref().map(|(pending, _)| pending.next_at());
 
-                if webserver_tx.send(response).is_err() {
-                    error!("Webserver did not get the status (receiver hung up)");
-                }
-            },
-            Ok(Some(LastEvent(webserver_tx))) => {
-                if webserver_tx.send(last_event.clone()).is_err() {
-                    error!("Webserver did not get the status (receiver hung up)");
+            async move {
+                match next_at {
+                    Some(instant) => time::sleep_until(instant.into()).await,
+                    None => std::future::pending().await,
                 }
+            }
+        };
+
+        let backup_tick = async {
+            match backup_timer.as_mut() {
+                Some(timer) => timer.tick().await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let backup_done = async {
+            match live_backup.as_mut() {
+                Some((task, _)) => task.await,
+                None => std::future::pending().await,
+            }
+        };
+
+        select! {
+            // Check for messages from the webserver
+            cmd = msg.recv() => match cmd {
+                // asking again while players are being warned skips the rest of the warnings
+                Some(StopServer(by, webserver_tx)) if countdown.is_some() || profile.stop_warnings.is_empty() => {
+                    if webserver_tx.send(StopOutcome::Stopping).is_err() {
+                        error!("Webserver did not get the stop outcome (receiver hung up)");
+                    }
+
+                    info!("Webserver requested shutdown, stopping Minecraft");
+
+                    let reason = format!("requested by {by}");
+                    try_stop_server(&mut mc_server, &mut rcon_client, profile, StateChange::because(&reason).by(by), events, last_event, &mut live_backup).await;
+                    break (StopReason::Requested, reason);
+                },
+                Some(StopServer(by, webserver_tx)) => {
+                    if webserver_tx.send(StopOutcome::CountingDown).is_err() {
+                        error!("Webserver did not get the stop outcome (receiver hung up)");
+                    }
+
+                    info!("Webserver requested shutdown, warning players before stopping Minecraft");
+                    countdown = Some((start_countdown(&format!("requested by {by}"), Some(by)), StopReason::Requested));
+                },
+                Some(CancelStop(by, webserver_tx)) => {
+                    let cancelled = countdown.take().is_some();
+
+                    if cancelled {
+                        info!("{by} cancelled the pending stop of {}", profile.id);
+                        cancel_countdown(&mut rcon_client).await;
+                        idle_begin = Instant::now();
+                    }
+
+                    if webserver_tx.send(cancelled).is_err() {
+                        error!("Webserver did not get the cancel outcome (receiver hung up)");
+                    }
+                },
+                Some(TakeBackup(by, webserver_tx)) => {
+                    info!("{by} asked for a backup of {}", profile.id);
+
+                    let started = match claim_backups(backups) {
+                        Ok((backups, claim)) => begin_live_backup(&mut rcon_client, backups, BackupKind::Manual, claim).await,
+                        Err(e) => Err(e),
+                    };
+
+                    match started {
+                        Ok(task) => live_backup = Some((task, Some(webserver_tx))),
+                        Err(e) => {
+                            if webserver_tx.send(Err(e)).is_err() {
+                                error!("Webserver did not get the backup (receiver hung up)");
+                            }
+                        },
+                    }
+                },
+                Some(RestoreBackup(_, _, webserver_tx)) => {
+                    if webserver_tx.send(Err(BackupError::Running)).is_err() {
+                        error!("Webserver did not get the restore (receiver hung up)");
+                    }
+                },
+                Some(Query(webserver_tx)) => {
+                    // query minecraft server and tell webserver result
+                    let response = query_minecraft(profile).await.ok();
+
+                    if webserver_tx.send(response).is_err() {
+                        error!("Webserver did not get the status (receiver hung up)");
+                    }
+                },
+                Some(LastEvent(webserver_tx)) => {
+                    if webserver_tx.send(last_event.clone()).is_err() {
+                        error!("Webserver did not get the status (receiver hung up)");
+                    }
+                },
+                Some(Rcon(command, webserver_tx)) => {
+                    info!("Running RCON command for the webserver: {command}");
+
+                    let response = rcon_client.run_command(&command).await
+                        .map_err(|e| RconCommandError::Failed(e.to_string()));
+
+                    if webserver_tx.send(response).is_err() {
+                        error!("Webserver did not get the RCON response (receiver hung up)");
+                    }
+                },
+                Some(other) => warn!("Webserver sent an invalid message: {other:?}"),
+                None => {
+                    error!("Webserver dropped sender while Minecraft was online, forcing Minecraft to close");
+
+                    let reason = "the webserver went away";
+                    try_stop_server(&mut mc_server, &mut rcon_client, profile, StateChange::because(reason), events, last_event, &mut live_backup).await;
+
+                    // send a messsage to the end-users listening on /events
+                    emit_event(ControlEvent::crashed_because(reason), events, last_event);
+                    break (StopReason::Requested, reason.to_string());
+                },
             },
-            Ok(Some(other)) => warn!("Webserver sent an invalid message: {other:?}"),
-            Ok(None) => {
-                error!("Webserver dropped sender while Minecraft was online, forcing Minecraft to close");
 
-                try_stop_server(&mut mc_server, &mut rcon_client).await;
+            // Notice immediately if Minecraft exits without being told to
+            status = mc_server.wait() => {
+                break match status {
+                    Ok(status) if status.success() => {
+                        info!("Minecraft exited on its own with {status}");
+                        (StopReason::Exited, format!("exited on its own with {status}"))
+                    },
+                    Ok(status) => {
+                        error!("Minecraft crashed: process exited with {status}");
 
-                // send a messsage to the end-users listening on /events
-                emit_event(Crashed, evt_sender, last_event);
-                break;
+                        // send a messsage to the end-users listening on /events
+                        emit_event(crashed_with(status), events, last_event);
+                        (StopReason::Crashed, format!("process exited with {status}"))
+                    },
+                    Err(e) => {
+                        error!("Unable to wait on the Minecraft process: {e}");
+
+                        let reason = format!("unable to wait on the process: {e}");
+                        try_stop_server(&mut mc_server, &mut rcon_client, profile, StateChange::because(&reason), events, last_event, &mut live_backup).await;
+                        emit_event(ControlEvent::crashed_because(&reason), events, last_event);
+                        (StopReason::Crashed, reason)
+                    },
+                };
             },
-            Err(_) => {}, // No messages
-        }
 
-        // Query number of players: if > 0, reset timer; if timer > timeout, stop server.
-        // Also, if query fails, we must close the server because we don't want it running
-        // indefinitely.
-        match mc_query::status("localhost", settings.minecraft_port).await {
-            Ok(status) => {
-                debug!("Queried Minecraft, got status {status:?}");
-                
-
-                // reset the idle timer if there are players online
-                if status.players.online > 0 {
-                    idle_begin = Instant::now();
-
-                    // emit event if server was previously not empty
-                    if !is_empty {
-                        is_empty = true;
-                        emit_event(Occupied, evt_sender, last_event);
+            // Follow players joining and leaving on the console. If lines were skipped, the next
+            // query catches up.
+            Ok(line) = console_lines.recv() => {
+                if let Some(event) = roster.follow_console(&line.text) {
+                    // somebody showing up means the server isn't idle after all
+                    if matches!(event, PlayerJoined { .. }) && matches!(countdown, Some((_, StopReason::Idle))) {
+                        info!("A player joined {}, no longer stopping it for being idle", profile.id);
+                        countdown = None;
+                        cancel_countdown(&mut rcon_client).await;
+                        idle_begin = Instant::now();
                     }
-                // emit event if server was previously empty
-                } else if is_empty {
-                    is_empty = false;
-                    emit_event(Empty, evt_sender, last_event);
+
+                    broadcast_event(event, events);
                 }
+            },
+
+            _ = schedule_timer.tick() => match schedule.advance() {
+                // keep Minecraft from idling out while a window is open
+                (_, Some(WindowMode::On)) => idle_begin = Instant::now(),
+                // Only act when windows open or close, so that a cancelled stop stays cancelled.
+                // These take over from an idle countdown, which players joining would call off.
+                (was, Some(WindowMode::Off)) if was != Some(WindowMode::Off) && replaceable(&countdown) => {
+                    info!("The schedule keeps {} off, stopping Minecraft", profile.id);
+                    countdown = Some((start_countdown("the schedule keeps the server off", None), StopReason::Scheduled));
+                },
+                (Some(WindowMode::On), None) if replaceable(&countdown) => {
+                    info!("A scheduled window closed, stopping {}", profile.id);
+                    countdown = Some((start_countdown("the scheduled window closed", None), StopReason::Scheduled));
+                },
+                _ => {},
+            },
+
+            _ = backup_tick => match claim_backups(backups) {
+                Ok((backups, claim)) => match begin_live_backup(&mut rcon_client, backups, BackupKind::Timer, claim).await {
+                    Ok(task) => live_backup = Some((task, None)),
+                    Err(e) => error!("Unable to back up {}: {e}", profile.id),
+                },
+                Err(e) => info!("Skipping the scheduled backup of {}: {e}", profile.id),
+            },
 
+            result = backup_done => {
+                let Some((_, reply)) = live_backup.take() else {
+                    continue;
+                };
+
+                resume_saving(&mut rcon_client).await;
+                backup::report(&profile.id, result, reply);
+            },
 
-                // Stop the server if it has been idle for too long
-                if Instant::now() - idle_begin > idle_timeout {
-                    info!("Idle period has expired, shutting down Minecraft");
-                    try_stop_server(&mut mc_server, &mut rcon_client).await;
-                    break;
+            _ = countdown_timer => {
+                let Some((pending, stop_reason)) = countdown.as_mut() else {
+                    continue;
+                };
+
+                match pending.tick() {
+                    Tick::Warn(secs) => {
+                        for command in pending.warning_commands(secs) {
+                            if let Err(e) = rcon_client.run_command(&command).await {
+                                warn!("Unable to warn players that Minecraft is stopping: {e}");
+                            }
+                        }
+                    },
+                    Tick::Done => {
+                        let stop_reason = *stop_reason;
+                        let reason = pending.reason.clone();
+
+                        let mut change = StateChange::because(&reason);
+                        if let Some(by) = pending.by.take() {
+                            change = change.by(by);
+                        }
+
+                        if stop_reason == StopReason::Idle {
+                            metrics.idle_shutdown();
+                        }
+
+                        try_stop_server(&mut mc_server, &mut rcon_client, profile, change, events, last_event, &mut live_backup).await;
+                        break (stop_reason, reason);
+                    },
                 }
             },
-            Err(e) => {
-                // If we can't get the status of the server, then it's either already dead or
-                // some unknown circumstance is causing it to run unsupervised.
-                // Either way, we can't pretend like the server is in a valid state, so we must make
-                // sure it is dead and then go back to idle.
-                warn!("Forcing server shutdown because a status query failed: {e:?}");
-                try_stop_server(&mut mc_server, &mut rcon_client).await;
-
-                // send a messsage to the end-users listening on /events
-                emit_event(Crashed, evt_sender, last_event);
-                
-                break;
+
+            // Query number of players: if > 0, reset timer; if timer > timeout, stop server.
+            // Also, if query fails, we must close the server because we don't want it running
+            // indefinitely.
+            _ = query_timer.tick() => match query_minecraft(profile).await {
+                Ok(response) => {
+                    debug!("Queried Minecraft, got {response:?}");
+
+                    metrics.queried(&response);
+                    metrics.sample_process(mc_server.id());
+
+                    for event in roster.reconcile(&response) {
+                        broadcast_event(event, events);
+                    }
+
+                    let status = response.status;
+
+                    // the proxy also knows about players who are still logging in
+                    let occupied = status.players.online > 0 || proxy.is_some_and(|p| p.player_count() > 0);
+
+                    // reset the idle timer if there are players online
+                    if occupied {
+                        idle_begin = Instant::now();
+
+                        if matches!(countdown, Some((_, StopReason::Idle))) {
+                            info!("Players are on {}, no longer stopping it for being idle", profile.id);
+                            countdown = None;
+                            cancel_countdown(&mut rcon_client).await;
+                        }
+
+                        // emit event if server was previously not empty
+                        if !is_empty {
+                            is_empty = true;
+                            emit_event(Occupied { change: StateChange::now() }, events, last_event);
+                        }
+                    // emit event if server was previously empty
+                    } else if is_empty {
+                        is_empty = false;
+                        emit_event(Empty { change: StateChange::now() }, events, last_event);
+                    }
+
+
+                    // Stop the server if it has been idle for too long, once players have been warned
+                    if countdown.is_none() && Instant::now() - idle_begin > idle_timeout {
+                        info!("Idle period has expired, shutting down Minecraft");
+                        countdown = Some((start_countdown("idle timeout expired", None), StopReason::Idle));
+                    }
+                },
+                Err(e) => {
+                    // The process is still running (otherwise the branch above would have
+                    // noticed), but it isn't answering. We can't pretend like the server is in a
+                    // valid state, so we must make sure it is dead and then go back to idle.
+                    warn!("Forcing server shutdown because a status query failed: {e:?}");
+
+                    let reason = format!("status query failed: {e}");
+                    try_stop_server(&mut mc_server, &mut rcon_client, profile, StateChange::because(&reason), events, last_event, &mut live_backup).await;
+
+                    // send a messsage to the end-users listening on /events
+                    emit_event(ControlEvent::crashed_because(&reason), events, last_event);
+                    
+                    break (StopReason::Crashed, reason);
+                },
             },
         }
+    };
+
+    for event in roster.clear() {
+        broadcast_event(event, events);
+    }
+
+    // Stopping Minecraft waits for the backup, so one that's still being taken means Minecraft
+    // exited by itself. There's nothing left to turn saving back on for.
+    if let Some((task, reply)) = live_backup {
+        backup::report(&profile.id, task.await, reply);
     }
+
+    reason
+}
+
+/// Go back to being offline after Minecraft couldn't be started, so that it can be started again
+fn start_failed(error: StartServerError, events: &EventLog, last_event: &mut ControlEvent) {
+    error!("Unable to start Minecraft: {error}");
+    emit_event(ControlEvent::Stopped { change: StateChange::because(format!("unable to start: {error}")) }, events, last_event);
+}
+
+/// Why Minecraft can't be started right now, if it can't
+fn start_blocked(schedule: &Schedule, backups: Option<&Arc<Backups>>) -> Option<&'static str> {
+    if schedule.mode() == Some(WindowMode::Off) {
+        return Some("the schedule keeps it off");
+    }
+    if backups.is_some_and(|b| b.is_restoring()) {
+        return Some("a backup is being restored");
+    }
+
+    None
+}
+
+/// Reserve the world for a backup, if backups are on and one isn't already being taken
+fn claim_backups(backups: Option<&Arc<Backups>>) -> Result<(&Arc<Backups>, OwnedMutexGuard<()>), BackupError> {
+    let backups = backups.ok_or(BackupError::Off)?;
+
+    Ok((backups, backups.try_claim()?))
+}
+
+/// Have Minecraft write everything to disk and stop saving, so that the world doesn't change while
+/// it's archived. Saving must be turned back on once the returned task is done.
+async fn begin_live_backup(
+    rcon_client: &mut RconClient,
+    backups: &Arc<Backups>,
+    kind: BackupKind,
+    claim: OwnedMutexGuard<()>
+) -> Result<BackupTask, BackupError> {
+    for command in ["save-off", "save-all flush"] {
+        if let Err(e) = rcon_client.run_command(command).await {
+            if rcon_client.run_command("save-on").await.is_err() {
+                warn!("Unable to have Minecraft start saving again");
+            }
+
+            return Err(BackupError::SaveOff(e.to_string()));
+        }
+    }
+
+    Ok(backups.create(kind, claim))
+}
+
+/// Have Minecraft start saving again after a live backup
+async fn resume_saving(rcon_client: &mut RconClient) {
+    if let Err(e) = rcon_client.run_command("save-on").await {
+        warn!("Unable to have Minecraft start saving again: {e}");
+    }
+}
+
+/// Whether the schedule may start its own countdown in place of the pending one. Stops somebody
+/// asked for are left alone.
+fn replaceable(countdown: &Option<(Countdown, StopReason)>) -> bool {
+    matches!(countdown, None | Some((_, StopReason::Idle)))
+}
+
+/// Let players know that the stop they were warned about is off
+async fn cancel_countdown(rcon_client: &mut RconClient) {
+    if let Err(e) = rcon_client.run_command(CANCEL_COMMAND).await {
+        warn!("Unable to tell players that Minecraft is no longer stopping: {e}");
+    }
+}
+
+/// Ask Minecraft for its status, and for everything else it knows if QUERY_PORT is set
+async fn query_minecraft(profile: &ServerProfile) -> std::io::Result<QueryResponse> {
+    let pinged_at = Instant::now();
+    let status = mc_query::status("localhost", profile.local_port()).await?;
+    let latency = pinged_at.elapsed();
+
+    let full = match profile.query_port {
+        Some(port) => match timeout(FULL_QUERY_TIMEOUT, mc_query::query::stat_full("localhost", port)).await {
+            Ok(Ok(full)) => Some(full),
+            Ok(Err(e)) => {
+                warn!("Query protocol request failed (is enable-query set?): {e}");
+                None
+            },
+            Err(_) => {
+                warn!("Query protocol request timed out (is enable-query set?)");
+                None
+            },
+        },
+        None => None,
+    };
+
+    Ok(QueryResponse { status, full, latency })
+}
+
+/// Build a `Crashed` event describing how the Minecraft process exited
+fn crashed_with(status: ExitStatus) -> ControlEvent {
+    #[cfg(unix)]
+    let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+    #[cfg(not(unix))]
+    let signal = None;
+
+    let change = StateChange::because(format!("process exited with {status}"));
+
+    ControlEvent::Crashed { change, code: status.code(), signal }
 }
 
 /// Describes the ways in which starting Minecraft can fail
@@ -199,32 +1148,61 @@ enum StartServerError {
     RconConnect,
     #[error("Unable to authenticate RCON with the Minecraft server")]
     RconAuth,
+    #[error(transparent)]
+    PreStartHook(HookError),
 }
 
-/// Spawn the Minecraft instance and connect with RCON
-async fn start_server(settings: &Env) -> Result<(Child, RconClient), StartServerError> {
+/// Spawn the Minecraft instance and connect with RCON, once any backup being taken is done and the
+/// pre-start hook has run. Minecraft's output is captured into `console`.
+async fn start_server<'a>(
+    profile: &'a ServerProfile,
+    console: &'a Arc<Console>,
+    backups: Option<&'a Arc<Backups>>,
+    change: StateChange
+) -> Result<(Child, RconClient), StartServerError> {
     
     use StartServerError::*;
 
-    let path = Path::new(&settings.server_path).join(&settings.run_command);
+    // the world mustn't change while it's being backed up
+    if let Some(backups) = backups {
+        backups.wait().await;
+    }
+
+    // run after the backup, since the hook may well change the server's files. The control loop
+    // keeps handling commands while this start is pending, so a slow hook doesn't hold it up.
+    if let Some(hook) = HookRun::new(Hook::PreStart, profile, &change) {
+        hook.run().await.map_err(PreStartHook)?;
+    }
+
+    let path = Path::new(&profile.server_path).join(&profile.run_command);
+
+    let mut command = Command::new(&path);
+    command.current_dir(&profile.server_path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    // Put the run script in its own process group so that signals sent during shutdown also reach
+    // the JVM, even if the script doesn't `exec` it
+    #[cfg(unix)]
+    command.process_group(0);
 
     // Attempt to execute "run.sh" on the server located at SERVER_PATH
-    let Ok(mut child) = std::process::Command::new(&path)
-        .current_dir(&settings.server_path)
-        .spawn() else {
+    let Ok(mut child) = command.spawn() else {
 
         error!("Could not execute run.sh. Does the server have one?");
 
         return Err(ProcessStart);
     };
 
+    console.capture(&mut child);
+
     // Attempt to get an RCON handle on the server
     let Ok(mut rcon_client) = attempt(
         attempt::Method::Timeout(Duration::from_secs(10)),
-        || RconClient::new("localhost", settings.rcon_port)
+        || RconClient::new("localhost", profile.rcon_port)
     ).await else {
         error!("Killing the Minecraft server: could not start RCON!");
-        if child.kill().is_err() {
+        if kill_server(&mut child).await.is_err() {
             warn!("Minecraft server was already dead.");
         }
         
@@ -232,9 +1210,9 @@ async fn start_server(settings: &Env) -> Result<(Child, RconClient), StartServer
     };
 
     // Attempt to authenticate the RCON client
-    if rcon_client.authenticate(&settings.rcon_password).await.is_err() {
+    if rcon_client.authenticate(&profile.rcon_password).await.is_err() {
         error!("Killing the Minecraft server: could not authenticate RCON!");
-        if child.kill().is_err() {
+        if kill_server(&mut child).await.is_err() {
             warn!("Minecraft server was already dead.");
         }
 
@@ -251,31 +1229,132 @@ enum StopServerError {
     ProcessKill,
 }
 
-async fn stop_server(mc_server: &mut Child, rcon: &mut RconClient) -> Result<(), StopServerError> {
+/// Shut down Minecraft and wait for the process to exit.
+/// 
+/// Shutdown escalates in three phases:
+/// 1. Send `stop` over RCON and wait up to MINECRAFT_STOP_TIMEOUT for Minecraft to save and exit
+/// 2. Send SIGTERM and wait up to MINECRAFT_TERM_TIMEOUT
+/// 3. Send SIGKILL
+/// 
+/// If RCON is unavailable the first phase is skipped.
+async fn stop_server(mc_server: &mut Child, rcon: &mut RconClient, profile: &ServerProfile) -> Result<(), StopServerError> {
     
     use StopServerError::*;
 
-    info!("Stopping server");
+    info!("Stopping server {}", profile.id);
     
     // Try gracefully shutting down the server with rcon
-    if rcon.run_command("stop").await.is_err() {
-        // if rcon fails, try to kill the process instead
-        mc_server.kill().map_err(|_| ProcessKill)?;
+    if rcon.run_command("stop").await.is_ok() {
+        let stop_timeout = Duration::from_secs(profile.minecraft_stop_timeout);
+
+        if let Ok(Ok(status)) = timeout(stop_timeout, mc_server.wait()).await {
+            info!("Minecraft exited with {status}");
+            return Ok(());
+        }
+
+        warn!("Minecraft did not exit within {}s of being told to stop", stop_timeout.as_secs());
+    } else {
+        warn!("Unable to send the stop command over RCON");
+    }
+
+    // Ask the operating system to end the process
+    if terminate_server(mc_server) {
+        let term_timeout = Duration::from_secs(profile.minecraft_term_timeout);
+
+        if let Ok(Ok(status)) = timeout(term_timeout, mc_server.wait()).await {
+            info!("Minecraft exited with {status} after being terminated");
+            return Ok(());
+        }
+
+        warn!("Minecraft did not exit within {}s of being terminated", term_timeout.as_secs());
     }
 
+    // Give up on being nice
+    kill_server(mc_server).await.map_err(|_| ProcessKill)?;
+    warn!("Minecraft was killed");
+
     Ok(())
 }
 
-async fn try_stop_server(mc_server: &mut Child, rcon_client: &mut RconClient) {
-    if let Err(e) = stop_server(mc_server, rcon_client).await {
+/// Announce that Minecraft is stopping and stop it, once the live backup being taken (if any) is
+/// done and the pre-stop hook has run
+async fn try_stop_server(
+    mc_server: &mut Child,
+    rcon_client: &mut RconClient,
+    profile: &ServerProfile,
+    change: StateChange,
+    events: &EventLog,
+    last_event: &mut ControlEvent,
+    live_backup: &mut Option<(BackupTask, Option<BackupReply>)>
+) {
+    let hook = HookRun::new(Hook::PreStop, profile, &change);
+    emit_event(ControlEvent::Stopping { change }, events, last_event);
+
+    // Minecraft writes the world as it stops, which mustn't happen while it's being archived
+    if let Some((task, reply)) = live_backup.take() {
+        info!("Waiting for the backup of {} to finish before stopping it", profile.id);
+
+        let result = task.await;
+        resume_saving(rcon_client).await;
+        backup::report(&profile.id, result, reply);
+    }
+
+    // Minecraft is stopped whether or not the hook worked
+    if let Some(hook) = hook {
+        if let Err(e) = hook.run().await {
+            error!("{e}");
+        }
+    }
+
+    if let Err(e) = stop_server(mc_server, rcon_client, profile).await {
         error!("There was a problem shutting down Minecraft: {e}");
     }
 }
 
-fn emit_event(event: ControlEvent, evt_sender: &mut broadcast::Sender<ControlEvent>, last_event: &mut ControlEvent) {
+/// Send SIGTERM to the Minecraft process group. Returns whether the signal was delivered.
+#[cfg(unix)]
+fn terminate_server(mc_server: &Child) -> bool {
+    // if there is no id, the process has already been reaped
+    let Some(pid) = mc_server.id() else {
+        return false;
+    };
+
+    // SAFETY: kill has no memory safety preconditions. The negative pid addresses the process
+    // group created in `start_server`.
+    unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGTERM) == 0 }
+}
+
+/// There is no SIGTERM outside of unix, so skip straight to killing the process
+#[cfg(not(unix))]
+fn terminate_server(_mc_server: &Child) -> bool {
+    false
+}
+
+/// Forcefully kill the Minecraft process group and reap the process
+async fn kill_server(mc_server: &mut Child) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if let Some(pid) = mc_server.id() {
+        // SAFETY: see `terminate_server`
+        unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL); }
+    }
+
+    mc_server.kill().await
+}
+
+fn emit_event(mut event: ControlEvent, events: &EventLog, last_event: &mut ControlEvent) {
+    if let Some(change) = event.change_mut() {
+        change.previous = Some(last_event.to_event_name());
+    }
+
     *last_event = event.clone();
 
-    if evt_sender.send(event).is_err() {
+    broadcast_event(event, events);
+}
+
+/// Send an event without making it the last event, for events that don't describe the server's
+/// state (e.g. players joining)
+fn broadcast_event(event: ControlEvent, events: &EventLog) {
+    if !events.push(event) {
         info!("Webserver not currently listening to events");
     }
 }
\ No newline at end of file
@@ -0,0 +1,87 @@
+// Warnings sent to players before Minecraft is stopped
+
+use std::time::{Duration, Instant};
+
+/// Sent to players when a stop they were warned about is called off
+pub const CANCEL_COMMAND: &str = "say The server is no longer stopping";
+
+/// A stop that players have been warned about. The control thread warns them whenever
+/// `next_at` passes, and stops Minecraft once there are no warnings left.
+pub struct Countdown {
+    /// Why Minecraft is stopping, which players are told as well
+    pub reason: String,
+    /// Who asked for the stop, if anybody did
+    pub by: Option<String>,
+    ends_at: Instant,
+    /// Warnings still to be sent, in seconds before the stop
+    warnings: Vec<u64>,
+    /// Whether to show players a title as well as a chat message
+    title: bool,
+}
+
+/// What to do when a countdown's `next_at` passes
+pub enum Tick {
+    /// Tell players how many seconds are left
+    Warn(u64),
+    Done,
+}
+
+impl Countdown {
+    /// Begin counting down from the longest of `warnings`, which is due right away. Without any
+    /// warnings the countdown is done right away.
+    pub fn new(reason: impl Into<String>, by: Option<String>, warnings: &[u64], title: bool) -> Self {
+        let mut warnings = warnings.to_vec();
+        warnings.sort_unstable_by(|a, b| b.cmp(a));
+        warnings.dedup();
+
+        Countdown {
+            reason: reason.into(),
+            by,
+            ends_at: Instant::now() + Duration::from_secs(warnings.first().copied().unwrap_or(0)),
+            warnings,
+            title,
+        }
+    }
+
+    /// When the next warning (or the stop) is due
+    pub fn next_at(&self) -> Instant {
+        match self.warnings.first() {
+            Some(secs) => self.ends_at - Duration::from_secs(*secs),
+            None => self.ends_at,
+        }
+    }
+
+    pub fn tick(&mut self) -> Tick {
+        if self.warnings.is_empty() {
+            return Tick::Done;
+        }
+
+        Tick::Warn(self.warnings.remove(0))
+    }
+
+    /// The RCON commands that warn players
+    pub fn warning_commands(&self, secs: u64) -> Vec<String> {
+        let left = time_left(secs);
+        let mut commands = vec![format!("say The server will stop in {left} ({})", self.reason)];
+
+        if self.title {
+            // the text is ours, so it never needs escaping
+            commands.push(format!(r#"title @a title {{"text":"Stopping in {left}","color":"red"}}"#));
+        }
+
+        commands
+    }
+}
+
+fn time_left(secs: u64) -> String {
+    let (amount, unit) = match secs {
+        s if s >= 60 && s % 60 == 0 => (s / 60, "minute"),
+        s => (s, "second"),
+    };
+
+    if amount == 1 {
+        format!("1 {unit}")
+    } else {
+        format!("{amount} {unit}s")
+    }
+}
@@ -1,25 +1,70 @@
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
 
-use mc_query::status::StatusResponse;
+use mc_query::query::FullStatResponse;
 use rocket::response::stream::Event;
-use rocket::tokio::{sync::{broadcast, mpsc, oneshot}, time::timeout};
-use rocket::State;
+use rocket::serde::json::{self, serde_json::json};
+use rocket::tokio::{sync::{broadcast::{self, error::RecvError}, mpsc, oneshot}, time::timeout};
 
-use crate::control::{ControlCmd, ControlEvent};
+use crate::console::{ConsoleLine, Stream};
+use crate::backup::{Backup, BackupError, Restore};
+use crate::control::{ControlCmd, ControlEvent, QueryResponse, RconCommandError, StopOutcome};
+use crate::event_log::{EventLog, LoggedEvent, Replay};
 
-/// Listens on the control thread's broadcast channel and converts messages to SSE events
-pub async fn await_events(events: &mut broadcast::Receiver<ControlEvent>) -> Option<Event> {
-        
-    let Ok(evt) = events.recv().await else {
-        error!("Control thread seems to have dropped its sender");
-        return None;
-    };
+/// How long to wait for a backup to be taken or restored. It carries on if this runs out.
+const BACKUP_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Listens on the control thread's broadcast channel and converts messages to SSE events. Falling
+/// behind catches up from the event log rather than skipping events.
+pub async fn await_events(replay: &mut Replay, log: &EventLog) -> Option<Vec<Event>> {
+
+    match replay.follow.recv().await {
+        Ok(evt) => {
+            replay.cursor = evt.id;
+            Some(vec![evt.to_event()])
+        },
+        Err(RecvError::Lagged(skipped)) => {
+            debug!("An event listener fell {skipped} events behind, catching up");
+            *replay = log.replay(Some(replay.cursor));
+            Some(replayed_events(replay))
+        },
+        // The log owns the sender and outlives the stream, so this can't happen. Ending the
+        // stream is the only sensible thing to do if it ever does.
+        Err(RecvError::Closed) => {
+            error!("The event log dropped its sender");
+            None
+        },
+    }
+}
+
+/// The SSE events for what a replay says was missed: a `resync` event with the current state if
+/// some events are gone for good, then the missed events
+pub fn replayed_events(replay: &mut Replay) -> Vec<Event> {
+    let resync = replay.resync.take().map(|state| {
+        let mut data = state.to_event_data();
+        data["state"] = state.to_event_name().into();
 
-    Some(Event::empty().event(evt.to_event_name()))
+        Event::json(&data).event("resync")
+    });
+
+    resync.into_iter()
+        .chain(replay.missed.drain(..).map(|evt| evt.to_event()))
+        .collect()
+}
+
+/// Follows Minecraft's console output and converts lines to SSE events. Returns `None` once the
+/// console goes away.
+pub async fn await_console(follow: &mut broadcast::Receiver<ConsoleLine>) -> Option<Event> {
+
+    match follow.recv().await {
+        Ok(line) => Some(line.to_event()),
+        // let the client know there's a gap rather than silently dropping lines
+        Err(RecvError::Lagged(skipped)) => Some(Event::data(format!("{skipped} lines were skipped")).event("lagged")),
+        Err(RecvError::Closed) => None,
+    }
 }
 
 /// Ask the control thread to query Minecraft
-pub async fn query_server(control: &State<mpsc::Sender<ControlCmd>>) -> Option<StatusResponse> {
+pub async fn query_server(control: &mpsc::Sender<ControlCmd>) -> Option<QueryResponse> {
 
     let (tx, rx) = oneshot::channel();
 
@@ -33,7 +78,7 @@ pub async fn query_server(control: &State<mpsc::Sender<ControlCmd>>) -> Option<S
     }
 }
 
-pub async fn get_last_event(control: &State<mpsc::Sender<ControlCmd>>) -> Option<ControlEvent> {
+pub async fn get_last_event(control: &mpsc::Sender<ControlCmd>) -> Option<ControlEvent> {
 
     let (tx, rx) = oneshot::channel();
 
@@ -47,18 +92,210 @@ pub async fn get_last_event(control: &State<mpsc::Sender<ControlCmd>>) -> Option
     }
 }
 
+/// Ask the control thread to stop Minecraft on behalf of `by`
+pub async fn stop_server(control: &mpsc::Sender<ControlCmd>, by: String) -> Option<StopOutcome> {
+
+    let (tx, rx) = oneshot::channel();
+
+    if control.send(ControlCmd::StopServer(by, tx)).await.is_err() {
+        println!("Control thread sender dropped");
+    }
+
+    match timeout(Duration::from_secs(10), rx).await {
+        Ok(Ok(outcome)) => Some(outcome),
+        _ => None,
+    }
+}
+
+/// Ask the control thread to call off a stop that players are being warned about. Returns whether
+/// there was one.
+pub async fn cancel_stop(control: &mpsc::Sender<ControlCmd>, by: String) -> Option<bool> {
+
+    let (tx, rx) = oneshot::channel();
+
+    if control.send(ControlCmd::CancelStop(by, tx)).await.is_err() {
+        error!("Control thread receiver dropped, unable to cancel the stop");
+    }
+
+    match timeout(Duration::from_secs(10), rx).await {
+        Ok(Ok(cancelled)) => Some(cancelled),
+        _ => None,
+    }
+}
+
+/// Ask the control thread to back the world up on behalf of `by`. Archiving a large world takes a
+/// while, so this waits for up to BACKUP_TIMEOUT.
+pub async fn take_backup(control: &mpsc::Sender<ControlCmd>, by: String) -> Option<Result<Backup, BackupError>> {
+
+    let (tx, rx) = oneshot::channel();
+
+    if control.send(ControlCmd::TakeBackup(by, tx)).await.is_err() {
+        error!("Control thread receiver dropped, unable to take a backup");
+    }
+
+    match timeout(BACKUP_TIMEOUT, rx).await {
+        Ok(Ok(backup)) => Some(backup),
+        _ => None,
+    }
+}
+
+/// Ask the control thread to restore the backup `id` on behalf of `by`, waiting as long as
+/// `take_backup` does
+pub async fn restore_backup(control: &mpsc::Sender<ControlCmd>, by: String, id: String) -> Option<Result<Restore, BackupError>> {
+
+    let (tx, rx) = oneshot::channel();
+
+    if control.send(ControlCmd::RestoreBackup(by, id, tx)).await.is_err() {
+        error!("Control thread receiver dropped, unable to restore a backup");
+    }
+
+    match timeout(BACKUP_TIMEOUT, rx).await {
+        Ok(Ok(restore)) => Some(restore),
+        _ => None,
+    }
+}
+
+/// Ask the control thread to run a command over RCON
+pub async fn run_rcon_command(control: &mpsc::Sender<ControlCmd>, command: String) -> Option<Result<String, RconCommandError>> {
+
+    let (tx, rx) = oneshot::channel();
+
+    if control.send(ControlCmd::Rcon(command, tx)).await.is_err() {
+        println!("Control thread sender dropped");
+    }
+
+    match timeout(Duration::from_secs(10), rx).await {
+        Ok(Ok(response)) => Some(response),
+        _ => None,
+    }
+}
+
 impl ControlEvent {
     pub fn to_event_name(&self) -> String {
 
         use ControlEvent::*;
 
         String::from(match self {
-            Started => "online",
+            Started { .. } => "online",
+            Starting { .. } => "starting",
+            Stopping { .. } => "stopping",
+            Stopped { .. } => "offline",
+            Crashed { .. } => "crashed",
+            CrashLoop { .. } => "crash_loop",
+            Empty { .. } => "empty",
+            Occupied { .. } => "occupied",
+            PlayerJoined { .. } => "player_joined",
+            PlayerLeft { .. } => "player_left",
+            Restored { .. } => "restored",
+        })
+    }
+
+    /// The JSON sent along with the event. Every event has a timestamp (in milliseconds since the
+    /// Unix epoch), and state changes also say what the previous state was, why it changed and who
+    /// asked for it.
+    pub fn to_event_data(&self) -> json::Value {
+        let mut data = match self {
+            ControlEvent::Crashed { code, signal, .. } => json!({
+                "code": code,
+                "signal": signal
+            }),
+            ControlEvent::PlayerJoined { name, uuid, .. } => json!({
+                "name": name,
+                "uuid": uuid
+            }),
+            ControlEvent::PlayerLeft { name, uuid, session_length, .. } => json!({
+                "name": name,
+                "uuid": uuid,
+                "session_length": session_length.as_secs()
+            }),
+            ControlEvent::Restored { backup, safety_backup, by, .. } => json!({
+                "backup": backup,
+                "safety_backup": safety_backup,
+                "by": by
+            }),
+            _ => json!({}),
+        };
+
+        let timestamp = self.timestamp().duration_since(UNIX_EPOCH).unwrap_or_default();
+        data["timestamp"] = json!(timestamp.as_millis() as u64);
+
+        if let Some(change) = self.change() {
+            data["previous"] = json!(change.previous);
+            data["reason"] = json!(change.reason);
+            data["by"] = json!(change.by);
+        }
+
+        data
+    }
+}
+impl QueryResponse {
+    /// Names of the players online. Without the Query protocol, this is only Minecraft's sample of
+    /// them, so the second value says whether the list is complete.
+    pub fn player_names(&self) -> (Vec<String>, bool) {
+        if let Some(full) = &self.full {
+            return (full.players.clone(), true);
+        }
+
+        let sample: Vec<_> = self.status.players.sample.iter()
+            .flatten()
+            .map(|p| p.name.clone())
+            .collect();
+        let complete = sample.len() >= self.status.players.online as usize;
+
+        (sample, complete)
+    }
+}
+
+/// Convert what the Query protocol says to JSON
+pub fn full_stat_json(full: &FullStatResponse) -> json::Value {
+    // e.g. "Paper on 1.20.4: WorldEdit 7.2.0; LuckPerms 5.4.0", or empty for vanilla
+    let plugins: Vec<_> = match full.plugins.split_once(": ") {
+        Some((_, list)) => list.split("; ").collect(),
+        None => Vec::new(),
+    };
+
+    json!({
+        "motd": full.motd,
+        "game_type": full.game_type,
+        "version": full.version,
+        "plugins": plugins,
+        "map": full.map,
+        "num_players": full.num_players,
+        "max_players": full.max_players,
+        "players": full.players
+    })
+}
+
+impl StopOutcome {
+    pub fn to_result_name(self) -> String {
+
+        use StopOutcome::*;
+
+        String::from(match self {
+            Stopping => "stopping",
+            CountingDown => "counting_down",
+            AlreadyOffline => "already_offline",
+            RestartCancelled => "restart_cancelled",
             Starting => "starting",
-            Stopped => "offline",
-            Crashed => "crashed",
-            Empty => "empty",
-            Occupied => "occupied",
         })
     }
-}
\ No newline at end of file
+}
+
+impl LoggedEvent {
+    pub fn to_event(&self) -> Event {
+        Event::json(&self.event.to_event_data())
+            .event(self.event.to_event_name())
+            .id(self.id.to_string())
+    }
+}
+
+impl ConsoleLine {
+    pub fn to_event(&self) -> Event {
+        let stream = match self.stream {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        };
+
+        Event::data(self.text.clone()).event(stream)
+    }
+}
@@ -0,0 +1,223 @@
+// Numbered control events, kept so that clients who reconnect can catch up
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::tokio::sync::broadcast;
+
+use crate::control::{ControlEvent, StateChange};
+
+/// How many new events a slow subscriber can fall behind before it has to catch up from the history
+const FOLLOW_CAPACITY: usize = 64;
+
+/// A control event and its id. Ids only ever increase, even across restarts of the application.
+#[derive(Debug, Clone)]
+pub struct LoggedEvent {
+    pub id: u64,
+    pub event: ControlEvent,
+}
+
+/// Numbers the control thread's events, keeps the most recent ones and broadcasts new ones
+pub struct EventLog {
+    history: Mutex<History>,
+    capacity: usize,
+    follow: broadcast::Sender<LoggedEvent>,
+}
+
+struct History {
+    events: VecDeque<LoggedEvent>,
+    next_id: u64,
+    /// The last state change, which outlives the history
+    state: ControlEvent,
+}
+
+/// What a subscriber missed, along with a receiver for what comes next
+pub struct Replay {
+    /// The events after the one asked for, oldest first
+    pub missed: Vec<LoggedEvent>,
+    /// Set to the current state if some of the events after the one asked for are no longer kept
+    pub resync: Option<ControlEvent>,
+    /// The id of the last event before `follow` begins
+    pub cursor: u64,
+    pub follow: broadcast::Receiver<LoggedEvent>,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Arc<Self> {
+        let (follow, _) = broadcast::channel(FOLLOW_CAPACITY);
+
+        // Counting from the current time in milliseconds keeps ids increasing after a restart,
+        // as long as fewer than 1000 events a second are sent
+        let first_id = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+
+        Arc::new(EventLog {
+            history: Mutex::new(History {
+                events: VecDeque::with_capacity(capacity),
+                next_id: first_id.max(1),
+                // the control thread starts out with Minecraft stopped too
+                state: ControlEvent::Stopped { change: StateChange::now() },
+            }),
+            capacity,
+            follow,
+        })
+    }
+
+    /// Number an event, keep it and send it to everybody following along. Returns whether
+    /// anybody is.
+    pub fn push(&self, event: ControlEvent) -> bool {
+        let mut history = self.history.lock().unwrap();
+
+        let logged = LoggedEvent { id: history.next_id, event };
+        history.next_id += 1;
+
+        if logged.event.change().is_some() {
+            history.state = logged.event.clone();
+        }
+
+        if history.events.len() >= self.capacity {
+            history.events.pop_front();
+        }
+        if self.capacity > 0 {
+            history.events.push_back(logged.clone());
+        }
+
+        self.follow.send(logged).is_ok()
+    }
+
+    /// Follow new events, without the history
+    pub fn subscribe(&self) -> broadcast::Receiver<LoggedEvent> {
+        self.follow.subscribe()
+    }
+
+    /// Get the events after `after` (none if it's `None`) along with a receiver for events sent
+    /// afterward. Both are taken together so that no event is missed or repeated in between.
+    ///
+    /// An id this log hasn't handed out yet (e.g. from before the clock was turned back) can't be
+    /// caught up from, so it gets a resync.
+    pub fn replay(&self, after: Option<u64>) -> Replay {
+        let history = self.history.lock().unwrap();
+        let cursor = history.next_id - 1;
+
+        let Some(after) = after.filter(|after| *after != cursor) else {
+            return Replay { missed: Vec::new(), resync: None, cursor, follow: self.follow.subscribe() };
+        };
+
+        if after > cursor {
+            return Replay { missed: Vec::new(), resync: Some(history.state.clone()), cursor, follow: self.follow.subscribe() };
+        }
+
+        let missed: Vec<_> = history.events.iter()
+            .filter(|logged| logged.id > after)
+            .cloned()
+            .collect();
+
+        // if the oldest event kept isn't the one right after, some fell out of the history
+        let complete = missed.first().is_some_and(|logged| logged.id == after + 1);
+
+        Replay {
+            missed,
+            resync: (!complete).then(|| history.state.clone()),
+            cursor,
+            follow: self.follow.subscribe(),
+        }
+    }
+}
+
+/// A request guard for the `Last-Event-ID` header, which browsers send when reconnecting to an
+/// event stream
+pub struct LastEventId(pub Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let id = request.headers().get_one("Last-Event-ID").and_then(|id| id.trim().parse().ok());
+
+        Outcome::Success(LastEventId(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn starting() -> ControlEvent {
+        ControlEvent::Starting { change: StateChange::now() }
+    }
+
+    /// A log holding `capacity` events, after `count` were pushed. Returns the log and the ids.
+    fn log_with(capacity: usize, count: usize) -> (Arc<EventLog>, Vec<u64>) {
+        let log = EventLog::new(capacity);
+        let first = log.replay(None).cursor + 1;
+
+        for _ in 0..count {
+            log.push(starting());
+        }
+
+        (log, (first..first + count as u64).collect())
+    }
+
+    fn ids(replay: &Replay) -> Vec<u64> {
+        replay.missed.iter().map(|logged| logged.id).collect()
+    }
+
+    #[test]
+    fn replays_missed_events() {
+        let (log, pushed) = log_with(10, 5);
+
+        let replay = log.replay(Some(pushed[1]));
+
+        assert_eq!(ids(&replay), pushed[2..]);
+        assert!(replay.resync.is_none());
+        assert_eq!(replay.cursor, pushed[4]);
+    }
+
+    #[test]
+    fn nothing_missed_when_up_to_date() {
+        let (log, pushed) = log_with(10, 3);
+
+        for after in [None, Some(pushed[2])] {
+            let replay = log.replay(after);
+
+            assert!(replay.missed.is_empty());
+            assert!(replay.resync.is_none());
+        }
+    }
+
+    #[test]
+    fn resyncs_when_history_is_gone() {
+        let (log, pushed) = log_with(3, 6);
+
+        let replay = log.replay(Some(pushed[0]));
+
+        // what's still kept is replayed after the resync
+        assert_eq!(ids(&replay), pushed[3..]);
+        assert!(matches!(replay.resync, Some(ControlEvent::Starting { .. })));
+    }
+
+    #[test]
+    fn resyncs_from_the_future() {
+        let (log, pushed) = log_with(10, 3);
+
+        let replay = log.replay(Some(pushed[2] + 100));
+
+        assert!(replay.missed.is_empty());
+        assert!(replay.resync.is_some());
+        assert_eq!(replay.cursor, pushed[2]);
+    }
+
+    #[test]
+    fn follows_after_the_cursor() {
+        let (log, pushed) = log_with(10, 2);
+
+        let mut replay = log.replay(Some(pushed[0]));
+        log.push(starting());
+
+        let next = replay.follow.try_recv().unwrap();
+        assert_eq!(next.id, replay.cursor + 1);
+    }
+}
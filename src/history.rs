@@ -0,0 +1,426 @@
+// Long-term record of server sessions, events and player counts, kept in SQLite
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rocket::serde::Serialize;
+use rocket::tokio::{self, select, sync::{broadcast::error::RecvError, mpsc}, time};
+use rusqlite::{Connection, OptionalExtension, params};
+use thiserror::Error;
+
+use crate::control::{ControlCmd, ControlEvent};
+use crate::endpoint_helpers::query_server;
+use crate::event_log::{EventLog, LoggedEvent};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS events (
+        id INTEGER PRIMARY KEY,
+        server TEXT NOT NULL,
+        event_id INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS events_by_time ON events (server, timestamp);
+
+    CREATE TABLE IF NOT EXISTS sessions (
+        id INTEGER PRIMARY KEY,
+        server TEXT NOT NULL,
+        started_at INTEGER NOT NULL,
+        started_by TEXT,
+        start_reason TEXT,
+        online_at INTEGER,
+        stopped_at INTEGER,
+        stopped_by TEXT,
+        stop_reason TEXT,
+        crashed INTEGER NOT NULL DEFAULT 0,
+        peak_players INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS sessions_by_time ON sessions (server, started_at);
+
+    CREATE TABLE IF NOT EXISTS player_sessions (
+        id INTEGER PRIMARY KEY,
+        server TEXT NOT NULL,
+        name TEXT NOT NULL,
+        uuid TEXT,
+        joined_at INTEGER NOT NULL,
+        left_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS player_sessions_by_time ON player_sessions (server, left_at);
+
+    CREATE TABLE IF NOT EXISTS player_samples (
+        server TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        online INTEGER NOT NULL,
+        max INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS player_samples_by_time ON player_samples (server, timestamp);
+";
+
+/// Sessions left open by an application that didn't get to record how they ended. They are closed
+/// at the last thing recorded about the server.
+const CLOSE_ABANDONED_SESSIONS: &str = "
+    UPDATE sessions SET
+        stopped_at = MAX(started_at, COALESCE(online_at, 0), COALESCE(
+            (SELECT MAX(timestamp) FROM events WHERE events.server = sessions.server), 0)),
+        stop_reason = 'the application stopped'
+    WHERE stopped_at IS NULL
+";
+
+/// The most rows a history query returns
+const MAX_ROWS: u32 = 10_000;
+
+/// Describes the ways in which reading or writing the history can fail
+#[derive(Error, Debug)]
+pub enum HistoryError {
+    #[error("History database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("History database task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// One run of Minecraft, from being asked to start until it stopped
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Session {
+    pub started_at: i64,
+    pub started_by: Option<String>,
+    pub start_reason: Option<String>,
+    /// Unset if Minecraft never came online
+    pub online_at: Option<i64>,
+    /// Unset while Minecraft is still running
+    pub stopped_at: Option<i64>,
+    pub stopped_by: Option<String>,
+    pub stop_reason: Option<String>,
+    pub crashed: bool,
+    pub peak_players: u32,
+}
+
+/// How much one player played over some period
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PlayerSummary {
+    pub name: String,
+    pub uuid: Option<String>,
+    pub sessions: u32,
+    pub playtime_secs: i64,
+    pub last_seen: i64,
+}
+
+/// How many players were online at some moment
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PlayerSample {
+    pub timestamp: i64,
+    pub online: u32,
+    pub max: u32,
+}
+
+/// An event as it was sent on /events
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RecordedEvent {
+    pub id: i64,
+    pub event: String,
+    pub data: rocket::serde::json::Value,
+}
+
+/// A period to look at, in milliseconds since the Unix epoch. Either end may be left open.
+#[derive(Debug, Clone, Copy, FromForm)]
+pub struct Period {
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+impl Period {
+    fn bounds(self) -> (i64, i64) {
+        (self.from.unwrap_or(0), self.to.unwrap_or(i64::MAX))
+    }
+}
+
+/// The history database, shared by every server
+pub struct History {
+    db: Mutex<Connection>,
+}
+
+impl History {
+    /// Open (or create) the database
+    pub fn open(path: &str) -> Result<Arc<Self>, HistoryError> {
+        let db = Connection::open(path)?;
+
+        db.execute_batch(SCHEMA)?;
+
+        let closed = db.execute(CLOSE_ABANDONED_SESSIONS, [])?;
+        if closed > 0 {
+            warn!("Closed {closed} server sessions that the history didn't see end");
+        }
+
+        Ok(Arc::new(History { db: Mutex::new(db) }))
+    }
+
+    /// Record everything a server's control thread says, and sample how many players are online
+    /// every `sample_interval` while Minecraft is running. `control` is weak so that the control
+    /// thread still notices when the webserver goes away.
+    pub async fn record(
+        self: Arc<Self>,
+        server: String,
+        log: Arc<EventLog>,
+        control: mpsc::WeakSender<ControlCmd>,
+        sample_interval: Duration
+    ) {
+        let mut replay = log.replay(None);
+        let mut samples = time::interval(sample_interval);
+        let mut running = false;
+
+        loop {
+            let missed = select! {
+                logged = replay.follow.recv() => match logged {
+                    Ok(logged) => {
+                        replay.cursor = logged.id;
+                        vec![logged]
+                    },
+                    // catch up rather than leave holes in the history
+                    Err(RecvError::Lagged(_)) => {
+                        replay = log.replay(Some(replay.cursor));
+                        std::mem::take(&mut replay.missed)
+                    },
+                    Err(RecvError::Closed) => break,
+                },
+
+                _ = samples.tick(), if running => {
+                    let Some(control) = control.upgrade() else {
+                        break;
+                    };
+
+                    if let Some(response) = query_server(&control).await {
+                        let players = &response.status.players;
+
+                        if let Err(e) = self.record_sample(&server, players.online, players.max).await {
+                            error!("Unable to record the player count of {server}: {e}");
+                        }
+                    }
+                    continue;
+                },
+            };
+
+            for logged in missed {
+                match logged.event {
+                    ControlEvent::Started { .. } | ControlEvent::Empty { .. } | ControlEvent::Occupied { .. } => running = true,
+                    ControlEvent::PlayerJoined { .. } | ControlEvent::PlayerLeft { .. } | ControlEvent::Restored { .. } => {},
+                    _ => running = false,
+                }
+
+                if let Err(e) = self.record_event(&server, logged).await {
+                    error!("Unable to record an event from {server}: {e}");
+                }
+            }
+        }
+    }
+
+    /// Sessions of a server that overlap the period, oldest first
+    pub async fn sessions(self: &Arc<Self>, server: &str, period: Period) -> Result<Vec<Session>, HistoryError> {
+        let server = server.to_string();
+        let (from, to) = period.bounds();
+
+        self.with_db(move |db| {
+            let mut statement = db.prepare("
+                SELECT started_at, started_by, start_reason, online_at, stopped_at, stopped_by,
+                    stop_reason, crashed, peak_players
+                FROM sessions
+                WHERE server = ?1 AND started_at <= ?3 AND COALESCE(stopped_at, ?3) >= ?2
+                ORDER BY started_at
+                LIMIT ?4
+            ")?;
+
+            let rows = statement.query_map(params![server, from, to, MAX_ROWS], |row| Ok(Session {
+                started_at: row.get(0)?,
+                started_by: row.get(1)?,
+                start_reason: row.get(2)?,
+                online_at: row.get(3)?,
+                stopped_at: row.get(4)?,
+                stopped_by: row.get(5)?,
+                stop_reason: row.get(6)?,
+                crashed: row.get(7)?,
+                peak_players: row.get(8)?,
+            }))?;
+
+            rows.collect()
+        }).await
+    }
+
+    /// Who played on a server during the period (most playtime first), and the player counts
+    /// sampled during it
+    pub async fn players(
+        self: &Arc<Self>,
+        server: &str,
+        period: Period
+    ) -> Result<(Vec<PlayerSummary>, Vec<PlayerSample>), HistoryError> {
+        let server = server.to_string();
+        let (from, to) = period.bounds();
+
+        self.with_db(move |db| {
+            // sessions are clipped to the period so that playtime only counts time within it
+            let mut statement = db.prepare("
+                SELECT name, MAX(uuid), COUNT(*), SUM(MIN(left_at, ?3) - MAX(joined_at, ?2)) / 1000,
+                    MAX(left_at)
+                FROM player_sessions
+                WHERE server = ?1 AND left_at >= ?2 AND joined_at <= ?3
+                GROUP BY name
+                ORDER BY 4 DESC
+                LIMIT ?4
+            ")?;
+
+            let players = statement.query_map(params![server, from, to, MAX_ROWS], |row| Ok(PlayerSummary {
+                name: row.get(0)?,
+                uuid: row.get(1)?,
+                sessions: row.get(2)?,
+                playtime_secs: row.get(3)?,
+                last_seen: row.get(4)?,
+            }))?.collect::<Result<_, _>>()?;
+
+            let mut statement = db.prepare("
+                SELECT timestamp, online, max
+                FROM player_samples
+                WHERE server = ?1 AND timestamp BETWEEN ?2 AND ?3
+                ORDER BY timestamp
+                LIMIT ?4
+            ")?;
+
+            let samples = statement.query_map(params![server, from, to, MAX_ROWS], |row| Ok(PlayerSample {
+                timestamp: row.get(0)?,
+                online: row.get(1)?,
+                max: row.get(2)?,
+            }))?.collect::<Result<_, _>>()?;
+
+            Ok((players, samples))
+        }).await
+    }
+
+    /// Events a server sent during the period, oldest first
+    pub async fn events(self: &Arc<Self>, server: &str, period: Period) -> Result<Vec<RecordedEvent>, HistoryError> {
+        let server = server.to_string();
+        let (from, to) = period.bounds();
+
+        self.with_db(move |db| {
+            let mut statement = db.prepare("
+                SELECT event_id, name, data
+                FROM events
+                WHERE server = ?1 AND timestamp BETWEEN ?2 AND ?3
+                ORDER BY id
+                LIMIT ?4
+            ")?;
+
+            let rows = statement.query_map(params![server, from, to, MAX_ROWS], |row| {
+                let data: String = row.get(2)?;
+
+                Ok(RecordedEvent {
+                    id: row.get(0)?,
+                    event: row.get(1)?,
+                    data: rocket::serde::json::from_str(&data).unwrap_or_default(),
+                })
+            })?;
+
+            rows.collect()
+        }).await
+    }
+
+    async fn record_event(self: &Arc<Self>, server: &str, logged: LoggedEvent) -> Result<(), HistoryError> {
+        let server = server.to_string();
+
+        self.with_db(move |db| {
+            let event = &logged.event;
+            let timestamp = millis(event.timestamp());
+            let change = event.change();
+            let by = change.and_then(|c| c.by.clone());
+            let reason = change.and_then(|c| c.reason.clone());
+
+            let tx = db.transaction()?;
+
+            tx.execute(
+                "INSERT INTO events (server, event_id, timestamp, name, data) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![server, logged.id as i64, timestamp, event.to_event_name(), event.to_event_data().to_string()],
+            )?;
+
+            let open: Option<i64> = tx.query_row(
+                "SELECT id FROM sessions WHERE server = ?1 AND stopped_at IS NULL",
+                [&server],
+                |row| row.get(0),
+            ).optional()?;
+
+            match (event, open) {
+                // asking again while Minecraft is starting doesn't start another session
+                (ControlEvent::Starting { .. }, None) => {
+                    tx.execute(
+                        "INSERT INTO sessions (server, started_at, started_by, start_reason) VALUES (?1, ?2, ?3, ?4)",
+                        params![server, timestamp, by, reason],
+                    )?;
+                },
+                (ControlEvent::Started { .. }, Some(id)) => {
+                    tx.execute("UPDATE sessions SET online_at = ?2 WHERE id = ?1", params![id, timestamp])?;
+                },
+                (ControlEvent::Stopping { .. }, Some(id)) => {
+                    tx.execute("UPDATE sessions SET stopped_by = ?2 WHERE id = ?1", params![id, by])?;
+                },
+                (ControlEvent::Crashed { .. }, Some(id)) => {
+                    tx.execute("UPDATE sessions SET crashed = 1 WHERE id = ?1", [id])?;
+
+                    // a failed automatic restart isn't followed by Stopped, so it ends here
+                    tx.execute(
+                        "UPDATE sessions SET stopped_at = ?2, stop_reason = ?3 WHERE id = ?1 AND online_at IS NULL",
+                        params![id, timestamp, reason],
+                    )?;
+                },
+                (ControlEvent::Stopped { .. }, Some(id)) => {
+                    tx.execute(
+                        "UPDATE sessions SET stopped_at = ?2, stop_reason = ?3 WHERE id = ?1",
+                        params![id, timestamp, reason],
+                    )?;
+                },
+                (ControlEvent::PlayerLeft { name, uuid, session_length, .. }, _) => {
+                    let joined_at = timestamp - session_length.as_millis() as i64;
+
+                    tx.execute(
+                        "INSERT INTO player_sessions (server, name, uuid, joined_at, left_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![server, name, uuid, joined_at, timestamp],
+                    )?;
+                },
+                _ => {},
+            }
+
+            tx.commit()
+        }).await
+    }
+
+    async fn record_sample(self: &Arc<Self>, server: &str, online: u32, max: u32) -> Result<(), HistoryError> {
+        let server = server.to_string();
+        let timestamp = millis(SystemTime::now());
+
+        self.with_db(move |db| {
+            db.execute(
+                "INSERT INTO player_samples (server, timestamp, online, max) VALUES (?1, ?2, ?3, ?4)",
+                params![server, timestamp, online, max],
+            )?;
+
+            db.execute(
+                "UPDATE sessions SET peak_players = MAX(peak_players, ?2) WHERE server = ?1 AND stopped_at IS NULL",
+                params![server, online],
+            )?;
+
+            Ok(())
+        }).await
+    }
+
+    /// SQLite blocks, so the database is only used off the async threads
+    async fn with_db<T: Send + 'static>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static
+    ) -> Result<T, HistoryError> {
+        let history = self.clone();
+
+        Ok(tokio::task::spawn_blocking(move || f(&mut history.db.lock().unwrap())).await??)
+    }
+}
+
+fn millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
@@ -0,0 +1,213 @@
+// Hooks: shell commands run as Minecraft starts, stops and crashes, e.g. to sync mods or to post to
+// a chat
+//
+// Hooks run in the server directory, with environment variables describing the server and why the
+// hook was run. Their output goes to the log.
+
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+
+use rocket::tokio::{self, process::Command, task::JoinHandle, time::{timeout, timeout_at, Instant}};
+use rocket::tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use thiserror::Error;
+
+use crate::config::ServerProfile;
+use crate::control::StateChange;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Hook {
+    /// Before Minecraft is spawned. If it fails, Minecraft isn't started.
+    PreStart,
+    PostStart,
+    /// Before Minecraft is told to stop, but not when it exits on its own
+    PreStop,
+    /// Whenever Minecraft has stopped, including when it crashed
+    PostStop,
+    OnCrash,
+}
+
+impl Hook {
+    /// The hook's name in the config file
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Hook::PreStart => "pre_start",
+            Hook::PostStart => "post_start",
+            Hook::PreStop => "pre_stop",
+            Hook::PostStop => "post_stop",
+            Hook::OnCrash => "on_crash",
+        }
+    }
+
+    fn command(self, profile: &ServerProfile) -> Option<&String> {
+        match self {
+            Hook::PreStart => profile.hook_pre_start.as_ref(),
+            Hook::PostStart => profile.hook_post_start.as_ref(),
+            Hook::PreStop => profile.hook_pre_stop.as_ref(),
+            Hook::PostStop => profile.hook_post_stop.as_ref(),
+            Hook::OnCrash => profile.hook_on_crash.as_ref(),
+        }
+    }
+}
+
+/// How long a hook's output is read for after it exits. Something it left running in the
+/// background may keep the output open for much longer.
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+/// Describes the ways in which a hook can fail
+#[derive(Error, Debug)]
+pub enum HookError {
+    #[error("Unable to run the {0} hook: {1}")]
+    Spawn(&'static str, std::io::Error),
+    #[error("The {0} hook failed with {1}")]
+    Failed(&'static str, ExitStatus),
+    #[error("The {0} hook was killed after running for {1}s")]
+    Timeout(&'static str, u64),
+}
+
+/// A hook that's ready to run, with everything it needs copied out of the profile so that it can
+/// run in the background
+pub struct HookRun {
+    hook: Hook,
+    server: String,
+    command: String,
+    server_path: String,
+    timeout: Duration,
+    env: Vec<(&'static str, String)>,
+}
+
+impl HookRun {
+    /// Prepare `profile`'s `hook` to run for `change`, if the profile has that hook
+    pub fn new(hook: Hook, profile: &ServerProfile, change: &StateChange) -> Option<Self> {
+        let command = hook.command(profile)?.clone();
+
+        let mut env = vec![
+            ("MC_HOOK", hook.as_str().to_string()),
+            ("MC_SERVER_ID", profile.id.clone()),
+            ("MC_SERVER_PATH", profile.server_path.clone()),
+            ("MC_PORT", profile.minecraft_port.to_string()),
+            ("MC_RCON_PORT", profile.rcon_port.to_string()),
+        ];
+
+        if let Some(reason) = &change.reason {
+            env.push(("MC_REASON", reason.clone()));
+        }
+        if let Some(by) = &change.by {
+            env.push(("MC_BY", by.clone()));
+        }
+
+        Some(HookRun {
+            hook,
+            server: profile.id.clone(),
+            command,
+            server_path: profile.server_path.clone(),
+            timeout: Duration::from_secs(profile.hook_timeout),
+            env,
+        })
+    }
+
+    /// Set another environment variable for the hook
+    pub fn env(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.env.push((name, value.to_string()));
+        self
+    }
+
+    /// Run the hook and wait for it to finish, killing it if it takes too long
+    pub async fn run(self) -> Result<(), HookError> {
+        let name = self.hook.as_str();
+
+        info!("Running the {name} hook for {}", self.server);
+
+        #[cfg(unix)]
+        let mut command = Command::new("sh");
+        #[cfg(unix)]
+        command.arg("-c");
+        #[cfg(not(unix))]
+        let mut command = Command::new("cmd");
+        #[cfg(not(unix))]
+        command.arg("/C");
+
+        command.arg(&self.command)
+            .current_dir(&self.server_path)
+            .envs(self.env.iter().map(|(name, value)| (*name, value)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        // so that whatever the hook starts can be killed along with it
+        #[cfg(unix)]
+        command.process_group(0);
+
+        let mut child = command.spawn().map_err(|e| HookError::Spawn(name, e))?;
+        let pid = child.id();
+
+        let readers = [
+            child.stdout.take().map(|stdout| self.log_output(stdout)),
+            child.stderr.take().map(|stderr| self.log_output(stderr)),
+        ];
+
+        // only the hook itself is waited for, not its output, which whatever it started in the
+        // background shares
+        let status = match timeout(self.timeout, child.wait()).await {
+            Ok(status) => status.map_err(|e| HookError::Spawn(name, e)),
+            Err(_) => {
+                kill_group(pid);
+                Err(HookError::Timeout(name, self.timeout.as_secs()))
+            },
+        };
+        drop(child);
+
+        let deadline = Instant::now() + OUTPUT_GRACE;
+        for reader in readers.into_iter().flatten() {
+            let abort = reader.abort_handle();
+            if timeout_at(deadline, reader).await.is_err() {
+                abort.abort();
+            }
+        }
+
+        let status = status?;
+        if !status.success() {
+            return Err(HookError::Failed(name, status));
+        }
+
+        Ok(())
+    }
+
+    /// Log each line the hook prints to `output` as it comes
+    fn log_output(&self, output: impl AsyncRead + Unpin + Send + 'static) -> JoinHandle<()> {
+        let prefix = format!("[{} {}]", self.server, self.hook.as_str());
+
+        tokio::spawn(async move {
+            let mut output = BufReader::new(output);
+            let mut line = Vec::new();
+
+            while output.read_until(b'\n', &mut line).await.is_ok_and(|read| read > 0) {
+                info!("{prefix} {}", String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']));
+                line.clear();
+            }
+        })
+    }
+
+    /// Run the hook in the background. Failures are only logged.
+    pub fn spawn(self) {
+        tokio::spawn(async move {
+            if let Err(e) = self.run().await {
+                error!("{e}");
+            }
+        });
+    }
+}
+
+/// Kill the process group of a hook that's taking too long
+#[cfg(unix)]
+fn kill_group(pid: Option<u32>) {
+    if let Some(pid) = pid {
+        // SAFETY: kill has no memory safety preconditions. The negative pid addresses the process
+        // group created in `HookRun::run`.
+        unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+    }
+}
+
+/// Without process groups, only the shell is killed (when it's dropped)
+#[cfg(not(unix))]
+fn kill_group(_pid: Option<u32>) {}
@@ -1,33 +1,78 @@
 #[macro_use] extern crate rocket;
 
 use std::net::Ipv4Addr;
+use std::path::PathBuf;
 
 use dotenvy::dotenv;
-use rocket::{Config, fairing::{Fairing, Kind, Info}, Request, http::Header, Response, tokio::sync};
+use rocket::{Config, Data, fairing::{Fairing, Kind, Info}, Request, http::{Header, uri::Origin}, Response};
 
 mod control;
 mod attempt;
 mod navigation;
 mod api;
 mod endpoint_helpers;
-mod env;
+mod config;
+mod auth;
+mod backup;
+mod chunk_store;
+mod console;
+mod countdown;
+mod event_log;
+mod history;
+mod hooks;
+mod metrics;
+mod ratelimit;
+mod proxy;
+mod registry;
+mod roster;
+mod schedule;
+mod wake;
 
 #[rocket::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    dotenv().expect(".env file should exist");
-    let settings = env::load_env()?;
-
-    let (cmd_tx, cmd_rx) = sync::mpsc::channel(5);
-    let (ev_tx, _) = sync::broadcast::channel(5);
-    // should   ^this receiver be dropped?
-    let s = settings.clone();
-    let evt_sub = ev_tx.clone();
-    // Start the server control thread
-    rocket::tokio::spawn(async move {
-        control::control(cmd_rx, ev_tx, s).await;
+    let mut config_path = None;
+    let mut gc_backups = false;
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>().into_iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = Some(PathBuf::from(args.next().ok_or("--config needs a path")?)),
+            // Helpers for filling in the users file
+            "hash-password" => return auth::print_password_hash(),
+            "new-token" => {
+                auth::print_new_token();
+                return Ok(());
+            },
+            // Needs the config, so it runs once that's loaded
+            "gc-backups" => gc_backups = true,
+            other => return Err(format!("Unrecognized argument: {other}").into()),
+        }
+    }
+
+    // .env is still supported, but optional now that there's a config file
+    let _ = dotenv();
+    let settings = config::load(config_path.as_deref()).unwrap_or_else(|e| {
+        // the Display impl points at the offending key, which Debug would bury
+        eprintln!("{e}");
+        std::process::exit(1);
     });
 
-    info!("Control thread started");
+    if gc_backups {
+        return backup::print_garbage_collection(&settings);
+    }
+
+    let accounts = auth::Accounts::load(&settings.users_file, settings.anonymous_role)?;
+
+    let history = match &settings.history_database {
+        Some(path) => Some(history::History::open(path)?),
+        None => None,
+    };
+
+    // Start a control thread for each server
+    let registry = registry::Registry::spawn(&settings, history);
+
+    let legacy_routes = LegacyRoutes { default: registry.default_server().profile.id.clone() };
+    let cors = Cors { origin: settings.cors_origin.clone() };
     
     // Start the web server
     let _ = rocket::build()
@@ -38,17 +83,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             ..Default::default()
         })
         .manage(settings)
-        .manage(cmd_tx) // Webserver can send messages to control thread
-        .manage(evt_sub) // /events can await signals from control thread. This is broadcast, so tx is needed to make new subscribers
-        .attach(Cors)
+        .manage(accounts)
+        .manage(registry) // Webserver can talk to each server's control thread
+        .attach(cors)
+        .attach(legacy_routes)
         .mount("/api", routes![
+            api::servers,
+            api::login,
+            api::logout,
+            api::whoami
+        ])
+        .mount("/api/servers", routes![
             api::query,
+            api::players,
             api::address,
             api::start,
             api::start_get,
+            api::stop,
+            api::stop_cancel,
             api::events,
-            api::last_event
+            api::console,
+            api::console_command,
+            api::last_event,
+            api::connections,
+            api::history_sessions,
+            api::history_players,
+            api::history_events,
+            api::backups,
+            api::create_backup,
+            api::restore,
+            api::schedule,
+            api::set_schedule
         ])
+        .mount("/", routes![api::metrics, api::preflight])
         .mount("/", rocket::fs::FileServer::from("ui/build"))
         // .mount("/", routes![navigation::index])
         .launch()
@@ -57,7 +124,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-struct Cors;
+/// Allows the web interface to be hosted somewhere else. Cross-origin requests are refused unless
+/// CORS_ORIGIN is set, since they would otherwise be able to use a visitor's login session. The
+/// preflight requests browsers send first are answered by `api::preflight`.
+struct Cors {
+    origin: Option<String>,
+}
 
 #[rocket::async_trait]
 impl Fairing for Cors {
@@ -69,12 +141,57 @@ impl Fairing for Cors {
     }
 
     async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
-        response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+        let Some(origin) = &self.origin else {
+            return;
+        };
+
+        response.set_header(Header::new("Access-Control-Allow-Origin", origin.clone()));
         response.set_header(Header::new(
             "Access-Control-Allow-Methods",
-            "POST, GET",
+            "GET, POST, PUT, OPTIONS",
         ));
-        // response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
-        // response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        response.set_header(Header::new("Access-Control-Allow-Headers", "Authorization, Content-Type"));
+        response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        response.set_header(Header::new("Vary", "Origin"));
+    }
+}
+
+/// Routes for a single server that can also be reached without a server id
+const LEGACY_ROUTES: &[&str] = &["query", "address", "start", "stop", "events", "console", "last-event", "connections", "players", "history", "schedule", "backups"];
+
+/// Keeps the routes from before there could be several servers working (e.g. `/api/start`) by
+/// rewriting them to the default server (`/api/servers/default/start`)
+struct LegacyRoutes {
+    default: String,
+}
+
+#[rocket::async_trait]
+impl Fairing for LegacyRoutes {
+    fn info(&self) -> Info {
+        Info {
+            name: "Single Server Routes",
+            kind: Kind::Request,
+        }
     }
-}
\ No newline at end of file
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        let uri = request.uri();
+        let Some(rest) = uri.path().as_str().strip_prefix("/api/") else {
+            return;
+        };
+
+        if !LEGACY_ROUTES.contains(&rest.split('/').next().unwrap_or_default()) {
+            return;
+        }
+
+        let rewritten = match uri.query() {
+            Some(query) => format!("/api/servers/{}/{rest}?{query}", self.default),
+            None => format!("/api/servers/{}/{rest}", self.default),
+        };
+
+        match Origin::parse_owned(rewritten) {
+            Ok(rewritten) => request.set_uri(rewritten),
+            Err(e) => warn!("Unable to rewrite {uri} for the default server: {e}"),
+        }
+    }
+}
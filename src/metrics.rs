@@ -0,0 +1,392 @@
+// Prometheus metrics
+
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::control::{ControlEvent, QueryResponse};
+use crate::event_log::LoggedEvent;
+
+/// Upper bounds of the start-up duration histogram's buckets, in seconds
+const STARTUP_BUCKETS: [f64; 10] = [5.0, 10.0, 15.0, 20.0, 30.0, 45.0, 60.0, 90.0, 120.0, 300.0];
+
+/// Every state the `state` gauge has a series for
+const STATES: [&str; 8] = ["offline", "starting", "online", "empty", "occupied", "stopping", "crashed", "crash_loop"];
+
+/// What one server's metrics currently read. The control thread reports what it sees when it
+/// queries Minecraft, and the rest is worked out from its events.
+pub struct Metrics {
+    readings: Mutex<Readings>,
+}
+
+#[derive(Clone)]
+struct Readings {
+    state: String,
+    players_online: Option<u32>,
+    ping: Option<Duration>,
+    starts: u64,
+    crashes: u64,
+    idle_shutdowns: u64,
+    startup: Histogram,
+    /// When Minecraft was last asked to start, if it hasn't come online since
+    starting_since: Option<SystemTime>,
+    process: Option<ProcessReading>,
+}
+
+#[derive(Clone, Default)]
+struct Histogram {
+    /// How many observations fell in each of `STARTUP_BUCKETS` (not cumulative)
+    buckets: [u64; STARTUP_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Resources used by Minecraft's process group
+#[derive(Clone)]
+struct ProcessReading {
+    rss_bytes: u64,
+    cpu_ticks: u64,
+    taken_at: Instant,
+    /// CPU cores in use, on average since the previous reading
+    cpu_usage: Option<f64>,
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Metrics {
+            readings: Mutex::new(Readings {
+                state: String::from("offline"),
+                players_online: None,
+                ping: None,
+                starts: 0,
+                crashes: 0,
+                idle_shutdowns: 0,
+                startup: Histogram::default(),
+                starting_since: None,
+                process: None,
+            }),
+        })
+    }
+
+    /// Follow the control thread's state, starts and crashes
+    pub async fn watch(self: Arc<Self>, mut events: broadcast::Receiver<LoggedEvent>) {
+        loop {
+            let event = match events.recv().await {
+                Ok(logged) => logged.event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+
+            let mut readings = self.readings.lock().unwrap();
+
+            match &event {
+                ControlEvent::Starting { change } => readings.starting_since = Some(change.timestamp),
+                ControlEvent::Started { change } => {
+                    readings.starts += 1;
+
+                    if let Some(since) = readings.starting_since.take() {
+                        let startup = change.timestamp.duration_since(since).unwrap_or_default();
+                        readings.startup.observe(startup.as_secs_f64());
+                    }
+                },
+                ControlEvent::Crashed { .. } => {
+                    readings.crashes += 1;
+                    readings.starting_since = None;
+                },
+                ControlEvent::Stopped { .. } => {
+                    readings.players_online = None;
+                    readings.ping = None;
+                    readings.process = None;
+                },
+                _ => {},
+            }
+
+            if event.change().is_some() {
+                readings.state = event.to_event_name();
+            }
+        }
+    }
+
+    /// Record what a status query found
+    pub fn queried(&self, response: &QueryResponse) {
+        let mut readings = self.readings.lock().unwrap();
+
+        readings.players_online = Some(response.status.players.online);
+        readings.ping = Some(response.latency);
+    }
+
+    pub fn idle_shutdown(&self) {
+        self.readings.lock().unwrap().idle_shutdowns += 1;
+    }
+
+    /// Measure the memory and CPU used by the process group led by `pid` (Minecraft's run script,
+    /// which the JVM may be a child of)
+    pub fn sample_process(&self, pid: Option<u32>) {
+        let usage = pid.and_then(process_group_usage);
+        let mut readings = self.readings.lock().unwrap();
+
+        readings.process = usage.map(|(rss_bytes, cpu_ticks)| {
+            let taken_at = Instant::now();
+
+            let cpu_usage = readings.process.as_ref().map(|previous| {
+                let ticks = cpu_ticks.saturating_sub(previous.cpu_ticks) as f64;
+                let elapsed = taken_at.duration_since(previous.taken_at).as_secs_f64();
+
+                ticks / clock_ticks_per_second() / elapsed.max(f64::EPSILON)
+            });
+
+            ProcessReading { rss_bytes, cpu_ticks, taken_at, cpu_usage }
+        });
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = STARTUP_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Write every server's metrics in the Prometheus text exposition format
+pub fn render<'a>(servers: impl Iterator<Item = (&'a str, &'a Metrics)>) -> String {
+    let servers: Vec<_> = servers
+        .map(|(id, metrics)| (id, metrics.readings.lock().unwrap().clone()))
+        .collect();
+
+    let mut out = String::new();
+
+    family(&mut out, "minecraft_players_online", "gauge", "Players online, as of the last status query");
+    for (id, r) in &servers {
+        if let Some(players) = r.players_online {
+            sample(&mut out, "minecraft_players_online", &[("server", id)], players);
+        }
+    }
+
+    family(&mut out, "minecraft_state", "gauge", "1 for the state the server is in, 0 for the others");
+    for (id, r) in &servers {
+        for state in STATES {
+            sample(&mut out, "minecraft_state", &[("server", id), ("state", state)], u8::from(r.state == state));
+        }
+    }
+
+    family(&mut out, "minecraft_ping_seconds", "gauge", "How long the last status query took");
+    for (id, r) in &servers {
+        if let Some(ping) = r.ping {
+            sample(&mut out, "minecraft_ping_seconds", &[("server", id)], ping.as_secs_f64());
+        }
+    }
+
+    counter(&mut out, &servers, "minecraft_starts_total", "Times Minecraft came online", |r| r.starts);
+    counter(&mut out, &servers, "minecraft_crashes_total", "Times Minecraft crashed or failed to start", |r| r.crashes);
+    counter(&mut out, &servers, "minecraft_idle_shutdowns_total", "Times Minecraft was stopped for being empty", |r| r.idle_shutdowns);
+
+    family(&mut out, "minecraft_startup_duration_seconds", "histogram", "Time from being asked to start until Minecraft was online");
+    for (id, r) in &servers {
+        let mut cumulative = 0;
+
+        for (bound, count) in STARTUP_BUCKETS.iter().zip(r.startup.buckets) {
+            cumulative += count;
+            sample(&mut out, "minecraft_startup_duration_seconds_bucket", &[("server", id), ("le", &bound.to_string())], cumulative);
+        }
+        sample(&mut out, "minecraft_startup_duration_seconds_bucket", &[("server", id), ("le", "+Inf")], r.startup.count);
+        sample(&mut out, "minecraft_startup_duration_seconds_sum", &[("server", id)], r.startup.sum);
+        sample(&mut out, "minecraft_startup_duration_seconds_count", &[("server", id)], r.startup.count);
+    }
+
+    family(&mut out, "minecraft_process_resident_memory_bytes", "gauge", "Resident memory of Minecraft's processes");
+    for (id, r) in &servers {
+        if let Some(process) = &r.process {
+            sample(&mut out, "minecraft_process_resident_memory_bytes", &[("server", id)], process.rss_bytes);
+        }
+    }
+
+    family(&mut out, "minecraft_process_cpu_usage", "gauge", "CPU cores used by Minecraft's processes, averaged between status queries");
+    for (id, r) in &servers {
+        if let Some(usage) = r.process.as_ref().and_then(|p| p.cpu_usage) {
+            sample(&mut out, "minecraft_process_cpu_usage", &[("server", id)], usage);
+        }
+    }
+
+    out
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn counter(out: &mut String, servers: &[(&str, Readings)], name: &str, help: &str, value: impl Fn(&Readings) -> u64) {
+    family(out, name, "counter", help);
+
+    for (id, r) in servers {
+        sample(out, name, &[("server", id)], value(r));
+    }
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    let labels: Vec<_> = labels.iter()
+        .map(|(label, value)| format!("{label}=\"{}\"", escape_label(value)))
+        .collect();
+
+    let _ = writeln!(out, "{name}{{{}}} {value}", labels.join(","));
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Add up the resident memory (in bytes) and CPU time (in clock ticks) of every process in a
+/// process group
+#[cfg(target_os = "linux")]
+fn process_group_usage(pgid: u32) -> Option<(u64, u64)> {
+    // SAFETY: sysconf has no memory safety preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(0) as u64;
+
+    let mut rss_bytes = 0;
+    let mut cpu_ticks = 0;
+    let mut found = false;
+
+    for entry in std::fs::read_dir("/proc").ok()?.flatten() {
+        // processes can exit while this runs, so unreadable entries are skipped
+        let Some(stat) = std::fs::read_to_string(entry.path().join("stat")).ok().and_then(|stat| parse_stat(&stat)) else {
+            continue;
+        };
+
+        if stat.pgrp != u64::from(pgid) {
+            continue;
+        }
+
+        found = true;
+        cpu_ticks += stat.cpu_ticks;
+        rss_bytes += stat.rss_pages * page_size;
+    }
+
+    found.then_some((rss_bytes, cpu_ticks))
+}
+
+/// The fields of /proc/<pid>/stat that `process_group_usage` adds up
+#[cfg(target_os = "linux")]
+#[derive(Debug, PartialEq, Eq)]
+struct ProcessStat {
+    pgrp: u64,
+    /// User and system time
+    cpu_ticks: u64,
+    rss_pages: u64,
+}
+
+/// Read a process' group, CPU time and resident memory from its /proc/<pid>/stat line. Fields are
+/// numbered from 1 as in proc(5).
+#[cfg(target_os = "linux")]
+fn parse_stat(stat: &str) -> Option<ProcessStat> {
+    // the command name in parentheses may contain spaces, so fields are counted from after it
+    let (_, rest) = stat.rsplit_once(") ")?;
+    let fields: Vec<_> = rest.split_whitespace().collect();
+    let field = |n: usize| fields.get(n - 3).and_then(|f| f.parse::<u64>().ok());
+
+    Some(ProcessStat {
+        pgrp: field(5)?,
+        cpu_ticks: field(14).unwrap_or(0) + field(15).unwrap_or(0),
+        rss_pages: field(24).unwrap_or(0),
+    })
+}
+
+/// There is no /proc to read outside of Linux
+#[cfg(not(target_os = "linux"))]
+fn process_group_usage(_pgid: u32) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn clock_ticks_per_second() -> f64 {
+    // SAFETY: see `process_group_usage`
+    unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clock_ticks_per_second() -> f64 {
+    100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(id: &str, metrics: &Metrics) -> Vec<String> {
+        render(std::iter::once((id, metrics))).lines().map(str::to_string).collect()
+    }
+
+    fn has(lines: &[String], line: &str) -> bool {
+        lines.iter().any(|l| l == line)
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let metrics = Metrics::new();
+        {
+            let mut readings = metrics.readings.lock().unwrap();
+            for startup in [3.0, 12.0, 12.5, 400.0] {
+                readings.startup.observe(startup);
+            }
+        }
+
+        let lines = rendered("a", &metrics);
+        let bucket = |le: &str, count: u64| format!("minecraft_startup_duration_seconds_bucket{{server=\"a\",le=\"{le}\"}} {count}");
+
+        assert!(has(&lines, &bucket("5", 1)));
+        assert!(has(&lines, &bucket("10", 1)));
+        assert!(has(&lines, &bucket("15", 3)));
+        assert!(has(&lines, &bucket("300", 3)));
+        // beyond the last bound, so only counted in +Inf
+        assert!(has(&lines, &bucket("+Inf", 4)));
+        assert!(has(&lines, "minecraft_startup_duration_seconds_sum{server=\"a\"} 427.5"));
+        assert!(has(&lines, "minecraft_startup_duration_seconds_count{server=\"a\"} 4"));
+    }
+
+    #[test]
+    fn every_family_has_help_and_type() {
+        let lines = rendered("a", &Metrics::new());
+
+        assert!(has(&lines, "# TYPE minecraft_startup_duration_seconds histogram"));
+        assert!(has(&lines, "# TYPE minecraft_starts_total counter"));
+        assert!(has(&lines, "minecraft_state{server=\"a\",state=\"offline\"} 1"));
+        assert!(has(&lines, "minecraft_state{server=\"a\",state=\"online\"} 0"));
+
+        let types = lines.iter().filter(|l| l.starts_with("# TYPE ")).count();
+        let helps = lines.iter().filter(|l| l.starts_with("# HELP ")).count();
+        assert_eq!(types, helps);
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let lines = rendered("a\"b\\c\nd", &Metrics::new());
+
+        assert!(has(&lines, "minecraft_starts_total{server=\"a\\\"b\\\\c\\nd\"} 0"));
+        // a newline in a label would have started a line of its own
+        assert!(lines.iter().all(|l| l.starts_with('#') || l.starts_with("minecraft_")));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parses_stat_with_odd_command_names() {
+        let stat = "1234 (java (my) server) S 1 4321 4321 0 -1 4194560 100 0 0 0 250 50 0 0 20 0 30 0 12345 1000000 5000 18446744073709551615\n";
+
+        assert_eq!(parse_stat(stat), Some(ProcessStat { pgrp: 4321, cpu_ticks: 300, rss_pages: 5000 }));
+        assert_eq!(parse_stat("1234 (java) S 1"), None);
+        assert_eq!(parse_stat("garbage"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn measures_own_process_group() {
+        // SAFETY: getpgrp has no preconditions
+        let pgid = unsafe { libc::getpgrp() } as u32;
+
+        let (rss_bytes, _) = process_group_usage(pgid).expect("the test runs in this group");
+        assert!(rss_bytes > 0);
+    }
+}
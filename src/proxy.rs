@@ -0,0 +1,312 @@
+// TCP proxy in front of Minecraft
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+use rocket::tokio::{self, try_join};
+use rocket::tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use rocket::tokio::net::{TcpListener, TcpStream};
+use rocket::tokio::sync::{broadcast::{self, error::RecvError}, mpsc};
+use rocket::tokio::time::timeout;
+
+use crate::config::ServerProfile;
+use crate::control::ControlEvent;
+use crate::event_log::LoggedEvent;
+use crate::wake::{self, Handshake, Join, ListedState, Replies};
+
+const COPY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Start of every PROXY protocol v2 header
+const PROXY_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Owns the public Minecraft port and forwards connections to the port Minecraft really listens on.
+/// While Minecraft is offline, connections are answered like the wake-on-join listener would (or
+/// refused if neither wake-on-join nor the offline status is enabled).
+pub struct Proxy {
+    public_port: u16,
+    backend_port: u16,
+    proxy_protocol: bool,
+    /// Set if wake-on-join or the offline status is enabled
+    replies: Option<Replies>,
+    online: AtomicBool,
+    listed: Mutex<ListedState>,
+    connections: Mutex<HashMap<u64, Arc<Connection>>>,
+    next_id: AtomicU64,
+}
+
+/// A client connection being forwarded to Minecraft
+pub struct Connection {
+    pub client: SocketAddr,
+    /// Whether the client is joining, as opposed to pinging the server
+    pub player: bool,
+    pub since: Instant,
+    /// Bytes sent by the client
+    pub bytes_in: AtomicU64,
+    /// Bytes sent to the client
+    pub bytes_out: AtomicU64,
+}
+
+/// What the control thread needs from the proxy
+pub struct ProxyLink {
+    pub proxy: Arc<Proxy>,
+    /// Players who tried to join while Minecraft was offline
+    pub joins: mpsc::Receiver<Join>,
+}
+
+impl Proxy {
+    /// Returns `None` unless the profile has a backend port to forward to
+    pub fn new(profile: &ServerProfile) -> Option<Arc<Self>> {
+        Some(Arc::new(Proxy {
+            public_port: profile.minecraft_port,
+            backend_port: profile.backend_port?,
+            proxy_protocol: profile.proxy_protocol,
+            replies: (profile.wake_on_join || profile.offline_status).then(|| Replies::new(profile)),
+            online: AtomicBool::new(false),
+            listed: Mutex::new(ListedState::Offline),
+            connections: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }))
+    }
+
+    /// Accept connections on the public port for as long as the application runs
+    pub async fn run(self: Arc<Self>, joins: mpsc::Sender<Join>) {
+        let address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.public_port));
+
+        let listener = match TcpListener::bind(address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Unable to listen on port {}, players won't be able to connect: {e}", self.public_port);
+                return;
+            },
+        };
+
+        info!("Forwarding port {} to {}", self.public_port, self.backend_port);
+
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Unable to accept a connection: {e}");
+                    continue;
+                },
+            };
+
+            let proxy = self.clone();
+            let joins = joins.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = proxy.handle_connection(stream, peer, &joins).await {
+                    debug!("Dropped connection from {peer}: {e}");
+                }
+            });
+        }
+    }
+
+    /// Follow the control thread to know whether there is a Minecraft server to forward to
+    pub async fn watch(self: Arc<Self>, mut events: broadcast::Receiver<LoggedEvent>) {
+        loop {
+            let event = match events.recv().await {
+                Ok(logged) => logged.event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+
+            match event {
+                ControlEvent::Started { .. } => self.online.store(true, Ordering::Relaxed),
+                ControlEvent::Stopped { .. } => self.online.store(false, Ordering::Relaxed),
+                _ => {},
+            }
+
+            let mut listed = self.listed.lock().unwrap();
+            *listed = listed.follow(&event);
+        }
+    }
+
+    /// The connections currently being forwarded
+    pub fn connections(&self) -> Vec<Arc<Connection>> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+
+    /// How many of the forwarded connections are players (this includes players who are still
+    /// logging in, which a status query doesn't count)
+    pub fn player_count(&self) -> usize {
+        self.connections.lock().unwrap().values().filter(|c| c.player).count()
+    }
+
+    async fn handle_connection(
+        &self,
+        mut stream: TcpStream,
+        peer: SocketAddr,
+        joins: &mpsc::Sender<Join>
+    ) -> Result<(), wake::ProtocolError> {
+        // Reading the handshake first tells players apart from pings
+        let handshake = timeout(wake::CONNECTION_TIMEOUT, wake::read_handshake(&mut stream)).await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        match &self.replies {
+            Some(replies) if !self.online.load(Ordering::Relaxed) => {
+                let listed = *self.listed.lock().unwrap();
+
+                timeout(wake::CONNECTION_TIMEOUT, wake::serve(stream, peer.ip(), handshake, replies, listed, joins)).await
+                    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+            },
+            _ => Ok(self.forward(stream, peer, handshake).await?),
+        }
+    }
+
+    async fn forward(&self, mut client: TcpStream, peer: SocketAddr, handshake: Handshake) -> io::Result<()> {
+        let mut backend = TcpStream::connect((Ipv4Addr::LOCALHOST, self.backend_port)).await?;
+
+        if self.proxy_protocol {
+            backend.write_all(&proxy_header(peer, client.local_addr()?)).await?;
+        }
+        backend.write_all(&handshake.to_bytes()).await?;
+
+        let tracked = self.track(peer, handshake.is_login());
+        let connection = &tracked.connection;
+
+        if connection.player {
+            info!("{peer} connected to Minecraft on port {}", self.public_port);
+        }
+
+        let (client_read, client_write) = client.split();
+        let (backend_read, backend_write) = backend.split();
+
+        let result = try_join!(
+            pipe(client_read, backend_write, &connection.bytes_in),
+            pipe(backend_read, client_write, &connection.bytes_out)
+        );
+
+        if connection.player {
+            info!("{peer} disconnected after {}s", connection.since.elapsed().as_secs());
+        }
+
+        result.map(|_| ())
+    }
+
+    fn track(&self, client: SocketAddr, player: bool) -> Tracked<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let connection = Arc::new(Connection {
+            client,
+            player,
+            since: Instant::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+        });
+
+        self.connections.lock().unwrap().insert(id, connection.clone());
+
+        Tracked { proxy: self, id, connection }
+    }
+}
+
+/// Removes a connection from the proxy's list when it ends, however it ends
+struct Tracked<'a> {
+    proxy: &'a Proxy,
+    id: u64,
+    connection: Arc<Connection>,
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        self.proxy.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Copy one direction of a connection, counting the bytes that pass through
+async fn pipe(
+    mut from: impl AsyncRead + Unpin,
+    mut to: impl AsyncWrite + Unpin,
+    counter: &AtomicU64
+) -> io::Result<()> {
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+
+    loop {
+        let n = from.read(&mut buf).await?;
+
+        if n == 0 {
+            // pass the half-close on so the other direction can finish
+            return to.shutdown().await;
+        }
+
+        to.write_all(&buf[..n]).await?;
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// A PROXY protocol v2 header telling Minecraft who the client really is
+fn proxy_header(client: SocketAddr, local: SocketAddr) -> Vec<u8> {
+    let mut header = PROXY_SIGNATURE.to_vec();
+
+    // version 2, PROXY command
+    header.push(0x21);
+
+    match (client.ip(), local.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            // TCP over IPv4
+            header.push(0x11);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&source.octets());
+            header.extend_from_slice(&destination.octets());
+        },
+        (source, destination) => {
+            // TCP over IPv6, with any IPv4 address mapped into it
+            header.push(0x21);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&to_ipv6(source).octets());
+            header.extend_from_slice(&to_ipv6(destination).octets());
+        },
+    }
+
+    header.extend_from_slice(&client.port().to_be_bytes());
+    header.extend_from_slice(&local.port().to_be_bytes());
+
+    header
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_header_for_ipv4() {
+        let client = SocketAddr::from(([203, 0, 113, 7], 50000));
+        let local = SocketAddr::from(([10, 0, 0, 1], 25565));
+
+        let header = proxy_header(client, local);
+
+        assert_eq!(header[..12], PROXY_SIGNATURE);
+        assert_eq!(header[12..16], [0x21, 0x11, 0x00, 12]);
+        assert_eq!(header[16..20], [203, 0, 113, 7]);
+        assert_eq!(header[20..24], [10, 0, 0, 1]);
+        assert_eq!(header[24..], [0xc3, 0x50, 0x63, 0xdd]);
+        assert_eq!(header.len(), 16 + 12);
+    }
+
+    #[test]
+    fn proxy_header_for_ipv6() {
+        let source = "2001:db8::7".parse::<Ipv6Addr>().unwrap();
+        let client = SocketAddr::from((source, 50000));
+        let local = SocketAddr::from(([10, 0, 0, 1], 25565));
+
+        let header = proxy_header(client, local);
+
+        assert_eq!(header[12..16], [0x21, 0x21, 0x00, 36]);
+        assert_eq!(header[16..32], source.octets());
+        // the IPv4 side is mapped into IPv6
+        assert_eq!(header[32..48], Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped().octets());
+        assert_eq!(header[48..], [0xc3, 0x50, 0x63, 0xdd]);
+        assert_eq!(header.len(), 16 + 36);
+    }
+}
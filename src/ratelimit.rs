@@ -0,0 +1,146 @@
+// Rate limiting for starting Minecraft
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::config::Settings;
+use crate::control::ControlEvent;
+use crate::event_log::LoggedEvent;
+
+/// Keeps people from booting Minecraft over and over again. A start request is refused if:
+/// - the same client asked less than START_CLIENT_INTERVAL ago
+/// - anybody asked less than START_GLOBAL_INTERVAL ago
+/// - Minecraft stopped or crashed less than START_COOLDOWN ago
+pub struct StartLimiter {
+    per_client: Duration,
+    global: Duration,
+    cooldown: Duration,
+    state: Mutex<LimiterState>,
+}
+
+#[derive(Default)]
+struct LimiterState {
+    clients: HashMap<IpAddr, Instant>,
+    last_start: Option<Instant>,
+    cooldown_until: Option<Instant>,
+}
+
+impl StartLimiter {
+    pub fn new(settings: &Settings) -> Arc<Self> {
+        Arc::new(StartLimiter {
+            per_client: Duration::from_secs(settings.start_client_interval),
+            global: Duration::from_secs(settings.start_global_interval),
+            cooldown: Duration::from_secs(settings.start_cooldown),
+            state: Mutex::new(LimiterState::default()),
+        })
+    }
+
+    /// Check whether `client` may start Minecraft right now, and count it as a start if so.
+    /// Otherwise returns how long the client should wait.
+    pub fn try_start(&self, client: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        // forget about clients that can already start again
+        state.clients.retain(|_, last| now - *last < self.per_client);
+
+        let wait = [
+            state.clients.get(&client).map(|last| *last + self.per_client),
+            state.last_start.map(|last| last + self.global),
+            state.cooldown_until,
+        ]
+            .into_iter()
+            .flatten()
+            .filter(|until| *until > now)
+            .max();
+
+        if let Some(until) = wait {
+            return Err(until - now);
+        }
+
+        state.clients.insert(client, now);
+        state.last_start = Some(now);
+
+        Ok(())
+    }
+
+    /// Listen to the control thread and begin a cooldown whenever Minecraft stops
+    pub async fn watch(self: Arc<Self>, mut events: broadcast::Receiver<LoggedEvent>) {
+        loop {
+            match events.recv().await {
+                // Stopped follows every crash, too
+                Ok(LoggedEvent { event: ControlEvent::Stopped { .. }, .. }) => {
+                    let mut state = self.state.lock().unwrap();
+                    state.cooldown_until = Some(Instant::now() + self.cooldown);
+                },
+                // one of the missed events may have been a stop
+                Err(RecvError::Lagged(_)) => {
+                    let mut state = self.state.lock().unwrap();
+                    state.cooldown_until = Some(Instant::now() + self.cooldown);
+                },
+                Ok(_) => {},
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+/// The address of whoever sent a request. If the connection comes from one of TRUSTED_PROXIES,
+/// that's the last address in X-Forwarded-For that isn't a trusted proxy too. Otherwise it's the
+/// address the connection comes from, whatever the headers say.
+pub struct ClientAddr(pub IpAddr);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientAddr {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(peer) = request.remote().map(|r| r.ip()) else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+
+        let trusted = request.rocket().state::<Settings>().map_or(&[][..], |s| &s.trusted_proxies);
+
+        if !trusted.contains(&peer) {
+            return Outcome::Success(ClientAddr(peer));
+        }
+
+        // each proxy appends the address it got the request from, so the ones at the end are
+        // the only ones that can't have been made up by the client
+        let forwarded = request.headers().get("X-Forwarded-For")
+            .flat_map(|header| header.split(','))
+            .map(|address| address.trim().parse::<IpAddr>())
+            .collect::<Vec<_>>();
+
+        let client = forwarded.into_iter().rev()
+            .map_while(Result::ok)
+            .find(|address| !trusted.contains(address));
+
+        Outcome::Success(ClientAddr(client.unwrap_or(peer)))
+    }
+}
+
+/// A `429 Too Many Requests` response telling the client when to try again
+pub struct TooManyRequests {
+    pub retry_after: Duration,
+}
+
+impl<'r> Responder<'r, 'static> for TooManyRequests {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        // round up so clients don't come back a moment too early
+        let seconds = self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0);
+        let message = format!("Too many requests, try again in {seconds} seconds");
+
+        Response::build_from(message.respond_to(request)?)
+            .status(Status::TooManyRequests)
+            .header(Header::new("Retry-After", seconds.to_string()))
+            .ok()
+    }
+}
@@ -0,0 +1,135 @@
+// The Minecraft servers managed by this instance
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::tokio::{self, sync::mpsc};
+
+use crate::backup::Backups;
+use crate::config::{ServerProfile, Settings};
+use crate::console::Console;
+use crate::control::{self, ControlCmd};
+use crate::event_log::EventLog;
+use crate::history::History;
+use crate::metrics::Metrics;
+use crate::proxy::{Proxy, ProxyLink};
+use crate::ratelimit::StartLimiter;
+use crate::schedule::Schedule;
+
+/// Everything the webserver needs to talk to one server's control thread
+pub struct ServerHandle {
+    pub profile: ServerProfile,
+    /// Webserver can send messages to the control thread
+    pub control: mpsc::Sender<ControlCmd>,
+    /// /events can await signals from the control thread, and replay the ones a client missed
+    pub events: Arc<EventLog>,
+    /// /console replays and follows Minecraft's output
+    pub console: Arc<Console>,
+    /// /start is rate limited
+    pub limiter: Arc<StartLimiter>,
+    /// Set if the application forwards players to Minecraft
+    pub proxy: Option<Arc<Proxy>>,
+    /// /schedule shows and changes when Minecraft is kept on or off
+    pub schedule: Arc<Schedule>,
+    /// /metrics reports what the control thread sees
+    pub metrics: Arc<Metrics>,
+    /// Set unless the history is turned off
+    pub history: Option<Arc<History>>,
+    /// Set if the world is backed up
+    pub backups: Option<Arc<Backups>>,
+}
+
+pub struct Registry {
+    servers: Vec<ServerHandle>,
+}
+
+impl Registry {
+    /// Start a control thread for every configured server, recording what happens to each in the
+    /// history if there is one
+    pub fn spawn(settings: &Settings, history: Option<Arc<History>>) -> Self {
+        let servers = settings.servers.iter().map(|profile| {
+            let (cmd_tx, cmd_rx) = mpsc::channel(5);
+            let events = EventLog::new(profile.event_history);
+            let console = Console::new(profile.console_buffer_lines);
+            let limiter = StartLimiter::new(settings);
+
+            let metrics = Metrics::new();
+            let schedule = Schedule::new(profile.schedule.clone());
+            let backups = Backups::new(profile);
+
+            tokio::spawn(limiter.clone().watch(events.subscribe()));
+            tokio::spawn(metrics.clone().watch(events.subscribe()));
+
+            let proxy = Proxy::new(profile);
+            let proxy_link = proxy.clone().map(|proxy| {
+                let (joins_tx, joins_rx) = mpsc::channel(5);
+
+                tokio::spawn(proxy.clone().watch(events.subscribe()));
+                tokio::spawn(proxy.clone().run(joins_tx));
+
+                ProxyLink { proxy, joins: joins_rx }
+            });
+
+            tokio::spawn(control::control(cmd_rx, events.clone(), profile.clone(), console.clone(), metrics.clone(), schedule.clone(), backups.clone(), limiter.clone(), proxy_link));
+
+            if let Some(history) = &history {
+                let sample_interval = Duration::from_secs(settings.history_sample_interval);
+                tokio::spawn(history.clone().record(profile.id.clone(), events.clone(), cmd_tx.downgrade(), sample_interval));
+            }
+
+            info!("Control thread for {} started", profile.id);
+
+            ServerHandle {
+                profile: profile.clone(),
+                control: cmd_tx,
+                events,
+                console,
+                limiter,
+                proxy,
+                schedule,
+                metrics,
+                history: history.clone(),
+                backups,
+            }
+        }).collect();
+
+        Registry { servers }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ServerHandle> {
+        self.servers.iter().find(|s| s.profile.id == id)
+    }
+
+    /// The server that the routes without a server id refer to
+    pub fn default_server(&self) -> &ServerHandle {
+        // config::load makes sure there is at least one
+        &self.servers[0]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ServerHandle> {
+        self.servers.iter()
+    }
+}
+
+/// A request guard for the server named in the path, for routes mounted at `/api/servers` that
+/// begin with `/<_>` (the server id). Fails with 404 if there is no such server.
+pub struct Server<'r>(pub &'r ServerHandle);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Server<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(registry) = request.rocket().state::<Registry>() else {
+            error!("The server registry is not being managed by Rocket");
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+
+        match request.param::<&str>(0).and_then(Result::ok).and_then(|id| registry.get(id)) {
+            Some(server) => Outcome::Success(Server(server)),
+            None => Outcome::Error((Status::NotFound, ())),
+        }
+    }
+}
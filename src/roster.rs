@@ -0,0 +1,131 @@
+// Who is online, pieced together from Minecraft's console and status queries
+
+use std::collections::HashMap;
+use std::time::{Instant, SystemTime};
+
+use crate::control::{ControlEvent, QueryResponse};
+
+/// Keeps track of the players online so that joins and leaves can be announced as they happen.
+///
+/// The console says right away when somebody joins or leaves. Queries catch anything the console
+/// missed (e.g. lines dropped by a slow reader, or a mod that changes the messages), but they are
+/// only made every so often and may not list everybody.
+#[derive(Default)]
+pub struct Roster {
+    players: HashMap<String, OnlinePlayer>,
+    /// UUIDs Minecraft logged while players were logging in
+    uuids: HashMap<String, String>,
+}
+
+struct OnlinePlayer {
+    uuid: Option<String>,
+    since: Instant,
+}
+
+impl Roster {
+    /// Look for players joining or leaving in a line of console output, e.g.
+    /// `[12:34:56] [Server thread/INFO]: Steve joined the game`
+    pub fn follow_console(&mut self, line: &str) -> Option<ControlEvent> {
+        // The first "]: " ends the prefix. Anything after it may have been typed by a player, so
+        // names are checked to keep chat messages from passing as joins.
+        let (_, message) = line.split_once("]: ")?;
+
+        if let Some(rest) = message.strip_prefix("UUID of player ") {
+            let (name, uuid) = rest.split_once(" is ")?;
+            self.uuids.insert(valid_name(name)?.to_string(), uuid.trim().to_string());
+
+            return None;
+        }
+
+        if let Some(name) = message.strip_suffix(" joined the game") {
+            // players who changed their name are announced as "New (formerly known as Old)"
+            let name = name.split(" (formerly known as ").next().unwrap_or(name);
+            let name = valid_name(name)?.to_string();
+            let uuid = self.uuids.remove(&name);
+
+            return self.join(name, uuid);
+        }
+
+        if let Some(name) = message.strip_suffix(" left the game") {
+            return self.leave(valid_name(name)?);
+        }
+
+        None
+    }
+
+    /// Bring the roster in line with a query. If the query's player list is incomplete, only
+    /// joins can be told from it.
+    pub fn reconcile(&mut self, response: &QueryResponse) -> Vec<ControlEvent> {
+        let (names, complete) = response.player_names();
+
+        let sample_uuids: HashMap<_, _> = response.status.players.sample.iter()
+            .flatten()
+            .map(|p| (p.name.as_str(), p.id.as_str()))
+            .collect();
+
+        let mut events = Vec::new();
+
+        if complete {
+            let gone: Vec<_> = self.players.keys()
+                .filter(|name| !names.contains(name))
+                .cloned()
+                .collect();
+
+            events.extend(gone.iter().filter_map(|name| self.leave(name)));
+        }
+
+        for name in names {
+            let uuid = sample_uuids.get(name.as_str()).map(|id| id.to_string());
+
+            if let Some(player) = self.players.get_mut(&name) {
+                // the console doesn't always say who somebody is
+                if player.uuid.is_none() {
+                    player.uuid = uuid;
+                }
+                continue;
+            }
+
+            events.extend(self.join(name, uuid));
+        }
+
+        events
+    }
+
+    /// Everybody leaves when Minecraft stops
+    pub fn clear(&mut self) -> Vec<ControlEvent> {
+        self.uuids.clear();
+
+        let names: Vec<_> = self.players.keys().cloned().collect();
+        names.iter().filter_map(|name| self.leave(name)).collect()
+    }
+
+    fn join(&mut self, name: String, uuid: Option<String>) -> Option<ControlEvent> {
+        if self.players.contains_key(&name) {
+            return None;
+        }
+
+        self.players.insert(name.clone(), OnlinePlayer { uuid: uuid.clone(), since: Instant::now() });
+
+        Some(ControlEvent::PlayerJoined { timestamp: SystemTime::now(), name, uuid })
+    }
+
+    fn leave(&mut self, name: &str) -> Option<ControlEvent> {
+        let player = self.players.remove(name)?;
+
+        Some(ControlEvent::PlayerLeft {
+            timestamp: SystemTime::now(),
+            name: name.to_string(),
+            uuid: player.uuid,
+            session_length: player.since.elapsed(),
+        })
+    }
+}
+
+/// Minecraft names are 3 to 16 letters, digits and underscores. Some servers let in shorter names
+/// or prefix Bedrock players with a dot, so this is a little more lenient.
+fn valid_name(name: &str) -> Option<&str> {
+    let valid = (1..=16).contains(&name.len())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+
+    valid.then_some(name)
+}
@@ -0,0 +1,350 @@
+// Windows of time in which a server is kept on or off, given as cron expressions
+
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use chrono::{Datelike, Days, Local, NaiveDate, NaiveDateTime, Timelike};
+use rocket::serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// How many days back to look for a window opening or closing. Anything that happens less than
+/// weekly can't be told apart from never.
+const LOOKBACK_DAYS: u64 = 8;
+
+const MONTH_NAMES: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const WEEKDAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// What a window does while it's open
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum WindowMode {
+    /// Start Minecraft when the window opens, don't stop it for idling, and stop it when the
+    /// window closes
+    On,
+    /// Stop Minecraft when the window opens and don't let it start until the window closes
+    Off,
+}
+
+/// A window opens whenever `start` matches and closes whenever `stop` matches
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", deny_unknown_fields)]
+pub struct Window {
+    pub start: Cron,
+    pub stop: Cron,
+    pub mode: WindowMode,
+}
+
+/// A five field cron expression (minute, hour, day of the month, month, day of the week), in local
+/// time. Fields take `*`, numbers, ranges (`1-5`), steps (`*/15`) and lists of those, and months
+/// and days of the week may also be given by their first three letters.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", try_from = "String", into = "String")]
+pub struct Cron {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    /// Sunday is 0
+    weekdays: u64,
+    /// Like cron, a day matches if either the day of the month or the day of the week does,
+    /// unless one of them is `*`
+    either_day: bool,
+}
+
+#[derive(Debug, Error)]
+#[error("Invalid cron expression `{expression}`: {problem}")]
+pub struct CronError {
+    expression: String,
+    problem: &'static str,
+}
+
+impl FromStr for Cron {
+    type Err = CronError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let error = |problem| CronError { expression: expression.to_string(), problem };
+
+        let fields: Vec<_> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(error("expected 5 fields"));
+        };
+
+        let mut weekday_bits = parse_field(weekdays, 0, 7, &WEEKDAY_NAMES).map_err(error)?;
+        // 7 is Sunday too
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits |= 1;
+        }
+
+        Ok(Cron {
+            expression: fields.join(" "),
+            minutes: parse_field(minutes, 0, 59, &[]).map_err(error)?,
+            hours: parse_field(hours, 0, 23, &[]).map_err(error)?,
+            days: parse_field(days, 1, 31, &[]).map_err(error)?,
+            months: parse_field(months, 1, 12, &MONTH_NAMES).map_err(error)?,
+            weekdays: weekday_bits,
+            either_day: !days.starts_with('*') && !weekdays.starts_with('*'),
+        })
+    }
+}
+
+impl TryFrom<String> for Cron {
+    type Error = CronError;
+
+    fn try_from(expression: String) -> Result<Self, Self::Error> {
+        expression.parse()
+    }
+}
+
+impl From<Cron> for String {
+    fn from(cron: Cron) -> Self {
+        cron.expression
+    }
+}
+
+impl Cron {
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let bit = |set: u64, n: u32| set & (1 << n) != 0;
+
+        let day = bit(self.days, date.day());
+        let weekday = bit(self.weekdays, date.weekday().num_days_from_sunday());
+        let day_matches = if self.either_day { day || weekday } else { day && weekday };
+
+        bit(self.months, date.month()) && day_matches
+    }
+
+    /// The latest minute at or before `time` that matches, if there was one in the last
+    /// LOOKBACK_DAYS days. Only matching days and hours are looked at, so this is cheap.
+    fn last_match(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        // the highest value in the set that's at most `limit`
+        let highest = |set: u64, limit: u32| {
+            let allowed = set & ((2 << limit) - 1);
+            (allowed != 0).then(|| 63 - allowed.leading_zeros())
+        };
+
+        for days_back in 0..=LOOKBACK_DAYS {
+            let date = time.date().checked_sub_days(Days::new(days_back))?;
+            if !self.matches_day(date) {
+                continue;
+            }
+
+            // only today is cut short by the current time
+            let today = days_back == 0;
+            let mut hour_limit = if today { time.hour() } else { 23 };
+
+            while let Some(hour) = highest(self.hours, hour_limit) {
+                let minute_limit = if today && hour == time.hour() { time.minute() } else { 59 };
+
+                if let Some(minute) = highest(self.minutes, minute_limit) {
+                    return date.and_hms_opt(hour, minute, 0);
+                }
+                if hour == 0 {
+                    break;
+                }
+
+                hour_limit = hour - 1;
+            }
+        }
+
+        None
+    }
+}
+
+/// Parse one field of a cron expression into a bit set of the values it matches. `names` are
+/// alternatives for the values from `min` on.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, &'static str> {
+    let value = |s: &str| -> Result<u32, &'static str> {
+        if let Some(i) = names.iter().position(|name| name.eq_ignore_ascii_case(s)) {
+            return Ok(min + i as u32);
+        }
+
+        s.parse().map_err(|_| "expected a number")
+    };
+
+    let mut bits = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<u32>().map_err(|_| "expected a number after /")?)),
+            None => (part, None),
+        };
+
+        let (low, high) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((low, high)) => (value(low)?, value(high)?),
+            // `5/15` means from 5 on
+            None if step.is_some() => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+
+        if low < min || high > max || low > high {
+            return Err("value out of range");
+        }
+        if step == Some(0) {
+            return Err("step can't be 0");
+        }
+
+        for n in (low..=high).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << n;
+        }
+    }
+
+    Ok(bits)
+}
+
+impl Window {
+    /// Whether the window opened more recently than it closed, in local time
+    fn is_open(&self, now: NaiveDateTime) -> bool {
+        match (self.start.last_match(now), self.stop.last_match(now)) {
+            // closing wins if both match the same minute
+            (Some(opened), Some(closed)) => opened > closed,
+            (opened, _) => opened.is_some(),
+        }
+    }
+}
+
+/// A server's windows, which can be changed while the application runs
+pub struct Schedule {
+    windows: Mutex<Vec<Window>>,
+    /// The mode the control thread last saw, to tell when windows open and close
+    seen: Mutex<Option<WindowMode>>,
+}
+
+impl Schedule {
+    pub fn new(windows: Vec<Window>) -> Arc<Self> {
+        Arc::new(Schedule {
+            windows: Mutex::new(windows),
+            seen: Mutex::new(None),
+        })
+    }
+
+    pub fn windows(&self) -> Vec<Window> {
+        self.windows.lock().unwrap().clone()
+    }
+
+    pub fn set_windows(&self, windows: Vec<Window>) {
+        *self.windows.lock().unwrap() = windows;
+    }
+
+    /// The mode of the open windows, if any are. If windows overlap, keeping the server off wins.
+    pub fn mode(&self) -> Option<WindowMode> {
+        let now = Local::now().naive_local();
+        let windows = self.windows.lock().unwrap();
+
+        let mut open = windows.iter().filter(|w| w.is_open(now)).map(|w| w.mode).peekable();
+        open.peek()?;
+
+        Some(if open.any(|mode| mode == WindowMode::Off) { WindowMode::Off } else { WindowMode::On })
+    }
+
+    /// The mode the last time this was called, and now. Only the control thread calls this.
+    pub fn advance(&self) -> (Option<WindowMode>, Option<WindowMode>) {
+        let mode = self.mode();
+        let previous = std::mem::replace(&mut *self.seen.lock().unwrap(), mode);
+
+        (previous, mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cron(expression: &str) -> Cron {
+        expression.parse().unwrap()
+    }
+
+    fn window(start: &str, stop: &str) -> Window {
+        Window { start: cron(start), stop: cron(stop), mode: WindowMode::On }
+    }
+
+    /// 2024-01-01 was a Monday
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap().and_hms_opt(hour, minute, 30).unwrap()
+    }
+
+    fn values(set: u64) -> Vec<u32> {
+        (0..64).filter(|n| set & (1 << n) != 0).collect()
+    }
+
+    #[test]
+    fn parses_fields() {
+        assert_eq!(values(cron("*/15 * * * *").minutes), [0, 15, 30, 45]);
+        assert_eq!(values(cron("5/20 * * * *").minutes), [5, 25, 45]);
+        assert_eq!(values(cron("0 9-11,20 * * *").hours), [9, 10, 11, 20]);
+        assert_eq!(values(cron("0 0-12/4 * * *").hours), [0, 4, 8, 12]);
+        assert_eq!(values(cron("0 0 * jan,Mar-may *").months), [1, 3, 4, 5]);
+        assert_eq!(values(cron("0 0 * * mon-fri").weekdays), [1, 2, 3, 4, 5]);
+        // 7 is Sunday too
+        assert_eq!(values(cron("0 0 * * 5-7").weekdays), [0, 5, 6, 7]);
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expression in ["* * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "5-1 * * * *", "*/0 * * * *", "* * * foo *"] {
+            assert!(expression.parse::<Cron>().is_err(), "{expression}");
+        }
+    }
+
+    #[test]
+    fn day_of_month_or_week() {
+        // the 1st or any Friday
+        let either = cron("0 0 1 * fri");
+        assert!(either.matches_day(at(1, 0, 0).date()));
+        assert!(either.matches_day(at(5, 0, 0).date()));
+        assert!(!either.matches_day(at(2, 0, 0).date()));
+
+        // with one of them `*`, only the other one counts
+        let weekdays = cron("0 0 * * fri");
+        assert!(!weekdays.matches_day(at(1, 0, 0).date()));
+        assert!(weekdays.matches_day(at(5, 0, 0).date()));
+    }
+
+    #[test]
+    fn last_match_looks_back() {
+        let evenings = cron("30 18,20 * * *");
+
+        assert_eq!(evenings.last_match(at(3, 20, 30)), Some(at(3, 20, 30).with_second(0).unwrap()));
+        assert_eq!(evenings.last_match(at(3, 20, 29)), Some(at(3, 18, 30).with_second(0).unwrap()));
+        assert_eq!(evenings.last_match(at(3, 18, 0)), Some(at(2, 20, 30).with_second(0).unwrap()));
+
+        // only in February, which is more than LOOKBACK_DAYS away
+        assert_eq!(cron("0 0 * feb *").last_match(at(20, 0, 0)), None);
+    }
+
+    #[test]
+    fn window_spanning_midnight() {
+        let night = window("0 22 * * *", "0 6 * * *");
+
+        assert!(night.is_open(at(2, 23, 0)));
+        assert!(night.is_open(at(3, 2, 0)));
+        assert!(!night.is_open(at(3, 6, 0)));
+        assert!(!night.is_open(at(3, 12, 0)));
+        assert!(night.is_open(at(3, 22, 0)));
+    }
+
+    #[test]
+    fn window_spanning_weekend() {
+        // Friday evening until Monday morning
+        let weekend = window("0 18 * * fri", "0 6 * * mon");
+
+        assert!(!weekend.is_open(at(5, 17, 59)));
+        assert!(weekend.is_open(at(5, 18, 0)));
+        assert!(weekend.is_open(at(7, 12, 0)));
+        assert!(weekend.is_open(at(8, 5, 59)));
+        assert!(!weekend.is_open(at(8, 6, 0)));
+        assert!(!weekend.is_open(at(10, 12, 0)));
+    }
+
+    #[test]
+    fn closing_wins_ties() {
+        assert!(!window("0 12 * * *", "0 12 * * *").is_open(at(3, 13, 0)));
+    }
+
+    #[test]
+    fn never_opened() {
+        assert!(!window("0 0 * feb *", "0 1 * feb *").is_open(at(3, 12, 0)));
+        // closing is optional as far as a window that opened is concerned
+        assert!(window("0 9 * * *", "0 0 * feb *").is_open(at(3, 12, 0)));
+    }
+}
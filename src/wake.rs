@@ -0,0 +1,576 @@
+// Wake-on-join and offline status: stands in for Minecraft on its port while it's offline
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+
+use rocket::serde::json::serde_json::json;
+use rocket::tokio::{self, select, io::{AsyncRead, AsyncReadExt, AsyncWriteExt}};
+use rocket::tokio::net::{TcpListener, TcpStream};
+use rocket::tokio::sync::{mpsc, oneshot};
+use rocket::tokio::time::timeout;
+use thiserror::Error;
+
+use crate::config::ServerProfile;
+use crate::control::ControlEvent;
+
+/// Handshakes and login requests are tiny, anything bigger isn't a Minecraft client
+const MAX_PACKET_LENGTH: usize = 32 * 1024;
+pub const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The states a client can ask for in its handshake
+const NEXT_STATE_STATUS: i32 = 1;
+const NEXT_STATE_LOGIN: i32 = 2;
+const NEXT_STATE_TRANSFER: i32 = 3;
+
+/// Describes the ways in which a client can fail to speak the protocol
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("VarInt is longer than 5 bytes")]
+    VarInt,
+    #[error("packet length {0} is out of range")]
+    PacketLength(i32),
+    #[error("packet ended early")]
+    Truncated,
+    #[error("string is not valid UTF-8")]
+    Utf8,
+    #[error("unexpected packet {0:#04x}")]
+    UnexpectedPacket(i32),
+    #[error("unknown next state {0}")]
+    NextState(i32),
+}
+
+/// A player trying to join while Minecraft is offline, who is told whether that starts it
+pub struct Join {
+    pub name: String,
+    pub client: IpAddr,
+    pub reply: oneshot::Sender<Result<(), WakeRefused>>,
+}
+
+/// Why a player trying to join didn't start Minecraft
+#[derive(Debug, Error)]
+pub enum WakeRefused {
+    #[error("it crashed too often")]
+    CrashLoop,
+    #[error("{0}")]
+    Blocked(&'static str),
+    #[error("it was started or stopped too recently")]
+    TooSoon(Duration),
+}
+
+/// What the server list shows for a server that isn't online
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListedState {
+    Offline,
+    Starting,
+    Crashed,
+}
+
+impl ListedState {
+    /// The state after `event`. A crash is remembered until Minecraft is started again, even
+    /// though it's followed by `Stopped`.
+    pub fn follow(self, event: &ControlEvent) -> Self {
+        use ControlEvent::*;
+
+        match event {
+            Starting { .. } => ListedState::Starting,
+            Crashed { .. } | CrashLoop { .. } => ListedState::Crashed,
+            Stopped { .. } if self == ListedState::Crashed => ListedState::Crashed,
+            Stopped { .. } => ListedState::Offline,
+            _ => self,
+        }
+    }
+}
+
+/// What the listener tells players
+#[derive(Clone)]
+pub struct Replies {
+    /// Whether players trying to join should start Minecraft
+    wake: bool,
+    offline_motd: String,
+    starting_motd: String,
+    crashed_motd: String,
+    retry_after: u64,
+    /// server-icon.png, which is read for every ping so that changes show up right away
+    icon_path: PathBuf,
+}
+
+impl Replies {
+    pub fn new(profile: &ServerProfile) -> Self {
+        Replies {
+            wake: profile.wake_on_join,
+            offline_motd: match profile.wake_on_join {
+                true => profile.wake_motd.clone(),
+                false => profile.offline_motd.clone(),
+            },
+            starting_motd: profile.starting_motd.clone(),
+            crashed_motd: profile.crashed_motd.clone(),
+            retry_after: profile.wake_retry_after,
+            icon_path: Path::new(&profile.server_path).join("server-icon.png"),
+        }
+    }
+
+    fn motd(&self, state: ListedState) -> &str {
+        match state {
+            ListedState::Offline => &self.offline_motd,
+            ListedState::Starting => &self.starting_motd,
+            ListedState::Crashed => &self.crashed_motd,
+        }
+    }
+
+    /// Why a player can't join right now
+    fn kick(&self, state: ListedState) -> String {
+        match (state, self.wake) {
+            (ListedState::Starting, _) | (_, true) => {
+                format!("The server is starting, try again in {} seconds", self.retry_after)
+            },
+            (state, false) => self.motd(state).to_string(),
+        }
+    }
+
+    /// Why a player who tried to join didn't start Minecraft
+    fn refused(&self, refused: &WakeRefused) -> String {
+        match refused {
+            WakeRefused::CrashLoop => self.crashed_motd.clone(),
+            WakeRefused::Blocked(why) => format!("The server can't be started because {why}"),
+            WakeRefused::TooSoon(wait) => {
+                // round up so players don't come back a moment too early
+                let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                format!("The server can't be started yet, try again in {seconds} seconds")
+            },
+        }
+    }
+
+    /// The server icon as a data URL, if there is one
+    async fn favicon(&self) -> Option<String> {
+        let icon = tokio::fs::read(&self.icon_path).await.ok()?;
+
+        Some(format!("data:image/png;base64,{}", BASE64.encode(icon)))
+    }
+}
+
+/// The first packet a client sends, which says what it wants from the server
+pub struct Handshake {
+    pub protocol: i32,
+    pub next_state: i32,
+    packet: Vec<u8>,
+}
+
+impl Handshake {
+    /// Whether the client wants to join, rather than ping the server
+    pub fn is_login(&self) -> bool {
+        matches!(self.next_state, NEXT_STATE_LOGIN | NEXT_STATE_TRANSFER)
+    }
+
+    /// The handshake as it was sent, for passing it on to Minecraft
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.packet.len() + 5);
+        write_varint(&mut bytes, self.packet.len() as i32);
+        bytes.extend_from_slice(&self.packet);
+
+        bytes
+    }
+}
+
+/// Answers server list pings with a MOTD saying why Minecraft is offline, and with wake-on-join,
+/// turns login attempts into requests to start Minecraft. Dropping it frees the port for Minecraft
+/// right away.
+pub struct WakeListener {
+    listener: TcpListener,
+    replies: Replies,
+    state: ListedState,
+    joins_tx: mpsc::Sender<Join>,
+    joins_rx: mpsc::Receiver<Join>,
+}
+
+impl WakeListener {
+    pub async fn bind(profile: &ServerProfile, state: ListedState) -> io::Result<Self> {
+        let address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, profile.minecraft_port));
+        let listener = TcpListener::bind(address).await?;
+        let (joins_tx, joins_rx) = mpsc::channel(5);
+
+        info!("Listening for players on port {} until {} is started", profile.minecraft_port, profile.id);
+
+        Ok(WakeListener {
+            listener,
+            replies: Replies::new(profile),
+            state,
+            joins_tx,
+            joins_rx,
+        })
+    }
+
+    /// Serve pings until somebody tries to join, and return who it is. Never returns without
+    /// wake-on-join.
+    pub async fn next_join(&mut self) -> Join {
+        loop {
+            select! {
+                conn = self.listener.accept() => match conn {
+                    Ok((mut stream, peer)) => {
+                        let replies = self.replies.clone();
+                        let state = self.state;
+                        let joins = self.joins_tx.clone();
+
+                        tokio::spawn(async move {
+                            let handle = async {
+                                let handshake = read_handshake(&mut stream).await?;
+                                serve(stream, peer.ip(), handshake, &replies, state, &joins).await
+                            };
+
+                            match timeout(CONNECTION_TIMEOUT, handle).await {
+                                Ok(Ok(())) => {},
+                                Ok(Err(e)) => debug!("Dropped connection from {peer}: {e}"),
+                                Err(_) => debug!("Connection from {peer} timed out"),
+                            }
+                        });
+                    },
+                    Err(e) => warn!("Unable to accept a connection: {e}"),
+                },
+                // the sender is owned by self, so this never yields None
+                Some(join) = self.joins_rx.recv() => break join,
+            }
+        }
+    }
+}
+
+/// Read the handshake off a new connection
+pub async fn read_handshake(stream: &mut (impl AsyncRead + Unpin)) -> Result<Handshake, ProtocolError> {
+    let packet = read_packet(stream).await?;
+    let mut reader = PacketReader::new(&packet);
+    reader.expect_id(0x00)?;
+
+    let protocol = reader.varint()?;
+    let _address = reader.string()?;
+    let _port = reader.u16()?;
+    let next_state = reader.varint()?;
+
+    Ok(Handshake { protocol, next_state, packet })
+}
+
+/// Stand in for Minecraft for the rest of a connection from `client`. Players trying to join are
+/// disconnected, and with wake-on-join, sent to `joins`.
+pub async fn serve(
+    mut stream: TcpStream,
+    client: IpAddr,
+    handshake: Handshake,
+    replies: &Replies,
+    state: ListedState,
+    joins: &mpsc::Sender<Join>
+) -> Result<(), ProtocolError> {
+    match handshake.next_state {
+        NEXT_STATE_STATUS => status(&mut stream, handshake.protocol, replies, state).await,
+        NEXT_STATE_LOGIN | NEXT_STATE_TRANSFER => login(&mut stream, client, replies, state, joins).await,
+        other => Err(ProtocolError::NextState(other)),
+    }
+}
+
+/// Server list ping: a status request, then optionally a ping to measure latency
+async fn status(stream: &mut TcpStream, protocol: i32, replies: &Replies, state: ListedState) -> Result<(), ProtocolError> {
+    loop {
+        let packet = read_packet(stream).await?;
+        let mut reader = PacketReader::new(&packet);
+
+        match reader.varint()? {
+            0x00 => {
+                // echoing the client's protocol keeps it from showing the server as outdated
+                let mut response = json!({
+                    "version": { "name": "Sleeping", "protocol": protocol },
+                    "players": { "max": 0, "online": 0 },
+                    "description": { "text": replies.motd(state) }
+                });
+
+                if let Some(favicon) = replies.favicon().await {
+                    response["favicon"] = favicon.into();
+                }
+
+                let mut data = Vec::new();
+                write_string(&mut data, &response.to_string());
+                write_packet(stream, 0x00, &data).await?;
+            },
+            0x01 => {
+                let payload = reader.bytes(8)?;
+                write_packet(stream, 0x01, payload).await?;
+
+                return Ok(());
+            },
+            other => return Err(ProtocolError::UnexpectedPacket(other)),
+        }
+    }
+}
+
+/// Login start: ask for Minecraft to be started (with wake-on-join) and disconnect the player,
+/// telling them whether it's starting
+async fn login(
+    stream: &mut TcpStream,
+    client: IpAddr,
+    replies: &Replies,
+    state: ListedState,
+    joins: &mpsc::Sender<Join>
+) -> Result<(), ProtocolError> {
+    let packet = read_packet(stream).await?;
+    let mut login_start = PacketReader::new(&packet);
+    login_start.expect_id(0x00)?;
+
+    let name = login_start.string()?;
+
+    let kick = match replies.wake {
+        true => {
+            let (reply, answer) = oneshot::channel();
+
+            // a full or closed channel, or a join that's dropped unanswered, means Minecraft is
+            // already being started
+            match joins.try_send(Join { name, client, reply }) {
+                Ok(()) => match answer.await {
+                    Ok(Err(refused)) => replies.refused(&refused),
+                    Ok(Ok(())) => replies.kick(ListedState::Starting),
+                    Err(_) => replies.kick(state),
+                },
+                Err(_) => replies.kick(state),
+            }
+        },
+        false => replies.kick(state),
+    };
+
+    let reason = json!({ "text": kick });
+
+    let mut data = Vec::new();
+    write_string(&mut data, &reason.to_string());
+    write_packet(stream, 0x00, &data).await?;
+
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+async fn read_packet(stream: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>, ProtocolError> {
+    let mut length: i32 = 0;
+
+    for i in 0..5 {
+        let byte = stream.read_u8().await?;
+        length |= i32::from(byte & 0x7f) << (7 * i);
+
+        if byte & 0x80 == 0 {
+            let size = usize::try_from(length)
+                .ok()
+                .filter(|size| (1..=MAX_PACKET_LENGTH).contains(size))
+                .ok_or(ProtocolError::PacketLength(length))?;
+
+            let mut packet = vec![0; size];
+            stream.read_exact(&mut packet).await?;
+
+            return Ok(packet);
+        }
+    }
+
+    Err(ProtocolError::VarInt)
+}
+
+async fn write_packet(stream: &mut TcpStream, id: i32, data: &[u8]) -> Result<(), ProtocolError> {
+    let mut body = Vec::with_capacity(data.len() + 5);
+    write_varint(&mut body, id);
+    body.extend_from_slice(data);
+
+    let mut packet = Vec::with_capacity(body.len() + 5);
+    write_varint(&mut packet, body.len() as i32);
+    packet.extend_from_slice(&body);
+
+    stream.write_all(&packet).await?;
+
+    Ok(())
+}
+
+fn write_varint(out: &mut Vec<u8>, value: i32) {
+    // VarInts encode the two's complement bits, so negative numbers take all 5 bytes
+    let mut value = value as u32;
+
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    write_varint(out, s.len() as i32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Reads the fields of a packet that has already been read off the stream
+struct PacketReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> PacketReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        PacketReader { buf }
+    }
+
+    fn expect_id(&mut self, id: i32) -> Result<(), ProtocolError> {
+        match self.varint()? {
+            actual if actual == id => Ok(()),
+            other => Err(ProtocolError::UnexpectedPacket(other)),
+        }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], ProtocolError> {
+        if self.buf.len() < n {
+            return Err(ProtocolError::Truncated);
+        }
+
+        let (bytes, rest) = self.buf.split_at(n);
+        self.buf = rest;
+
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<i32, ProtocolError> {
+        let mut value: i32 = 0;
+
+        for i in 0..5 {
+            let byte = self.bytes(1)?[0];
+            value |= i32::from(byte & 0x7f) << (7 * i);
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(ProtocolError::VarInt)
+    }
+
+    fn u16(&mut self) -> Result<u16, ProtocolError> {
+        let bytes = self.bytes(2)?;
+
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn string(&mut self) -> Result<String, ProtocolError> {
+        let length = self.varint()?;
+        let length = usize::try_from(length).map_err(|_| ProtocolError::Truncated)?;
+
+        std::str::from_utf8(self.bytes(length)?)
+            .map(str::to_string)
+            .map_err(|_| ProtocolError::Utf8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(value: i32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, value);
+        bytes
+    }
+
+    /// A packet with its length in front, as it's sent
+    fn framed(packet: &[u8]) -> Vec<u8> {
+        let mut bytes = varint(packet.len() as i32);
+        bytes.extend_from_slice(packet);
+        bytes
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        let cases: [(i32, &[u8]); 6] = [
+            (0, &[0x00]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (25565, &[0xdd, 0xc7, 0x01]),
+            (i32::MAX, &[0xff, 0xff, 0xff, 0xff, 0x07]),
+            // negative numbers take all 5 bytes
+            (-1, &[0xff, 0xff, 0xff, 0xff, 0x0f]),
+        ];
+
+        for (value, encoded) in cases {
+            assert_eq!(varint(value), encoded, "{value}");
+
+            let mut reader = PacketReader::new(encoded);
+            assert_eq!(reader.varint().unwrap(), value);
+            assert!(reader.buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn rejects_bad_varints() {
+        // a 6th byte is never allowed
+        let mut reader = PacketReader::new(&[0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        assert!(matches!(reader.varint(), Err(ProtocolError::VarInt)));
+
+        let mut reader = PacketReader::new(&[0x80, 0x80]);
+        assert!(matches!(reader.varint(), Err(ProtocolError::Truncated)));
+    }
+
+    #[test]
+    fn rejects_short_fields() {
+        let mut reader = PacketReader::new(&[0x05, b'a', b'b']);
+        assert!(matches!(reader.string(), Err(ProtocolError::Truncated)));
+
+        let mut reader = PacketReader::new(&[0x01]);
+        assert!(matches!(reader.u16(), Err(ProtocolError::Truncated)));
+
+        let mut reader = PacketReader::new(&[0x02, 0xff, 0xfe]);
+        assert!(matches!(reader.string(), Err(ProtocolError::Utf8)));
+    }
+
+    #[rocket::async_test]
+    async fn reads_packets() {
+        let bytes = framed(&[0x00, 0x01, 0x02]);
+        assert_eq!(read_packet(&mut &bytes[..]).await.unwrap(), [0x00, 0x01, 0x02]);
+
+        let largest = framed(&vec![0; MAX_PACKET_LENGTH]);
+        assert_eq!(read_packet(&mut &largest[..]).await.unwrap().len(), MAX_PACKET_LENGTH);
+    }
+
+    #[rocket::async_test]
+    async fn rejects_bad_packets() {
+        let empty = varint(0);
+        assert!(matches!(read_packet(&mut &empty[..]).await, Err(ProtocolError::PacketLength(0))));
+
+        let oversized = varint(MAX_PACKET_LENGTH as i32 + 1);
+        assert!(matches!(read_packet(&mut &oversized[..]).await, Err(ProtocolError::PacketLength(_))));
+
+        let negative = varint(-1);
+        assert!(matches!(read_packet(&mut &negative[..]).await, Err(ProtocolError::PacketLength(-1))));
+
+        let long_length = [0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert!(matches!(read_packet(&mut &long_length[..]).await, Err(ProtocolError::VarInt)));
+
+        let mut truncated = framed(&[0x00, 0x01, 0x02]);
+        truncated.pop();
+        assert!(matches!(read_packet(&mut &truncated[..]).await, Err(ProtocolError::Io(_))));
+    }
+
+    #[rocket::async_test]
+    async fn handshakes_are_passed_on_unchanged() {
+        let mut packet = vec![0x00];
+        packet.extend(varint(767));
+        write_string(&mut packet, "mc.example.com");
+        packet.extend_from_slice(&25565u16.to_be_bytes());
+        packet.extend(varint(NEXT_STATE_LOGIN));
+        let bytes = framed(&packet);
+
+        let handshake = read_handshake(&mut &bytes[..]).await.unwrap();
+
+        assert_eq!(handshake.protocol, 767);
+        assert!(handshake.is_login());
+        assert_eq!(handshake.to_bytes(), bytes);
+    }
+
+    #[rocket::async_test]
+    async fn rejects_other_first_packets() {
+        let bytes = framed(&[0x01, 0x00]);
+        assert!(matches!(read_handshake(&mut &bytes[..]).await, Err(ProtocolError::UnexpectedPacket(0x01))));
+    }
+}
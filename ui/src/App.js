@@ -2,17 +2,30 @@ import logo from './logo.svg';
 import './App.css';
 import React, { useState, useEffect } from 'react';
 import QueryData from './components/QueryData';
+import Login from './components/Login';
 import getLastEvent from './helpers/query'
 
 function App() {
     
+  const [user, setUser] = useState(null);
+  const [start_err, setStartErr] = useState(null);
+
   const startServer = async () => {
-    await fetch("http://162.232.250.170/api/start", { method: "POST" });
+    const res = await fetch("http://162.232.250.170/api/start", { method: "POST", credentials: "include" });
+
+    if (res.status === 401 || res.status === 403) {
+      setStartErr("Log in as somebody who may start the server first");
+    } else if (!res.ok) {
+      setStartErr(await res.text());
+    } else {
+      setStartErr(null);
+    }
   };
 
   const [mc_last_event, setMcLastEvent] = useState("offline");
   const [mc_last_event_err, setMcLastEventErr] = useState(null);
   const [init_loading, setInitLoading] = useState(true);
+  const [players, setPlayers] = useState([]);
 
   useEffect(() => {
     console.log(process.env.ADDRESS_HINT);
@@ -20,13 +33,28 @@ function App() {
     getLastEvent(setMcLastEvent, setMcLastEventErr, setInitLoading);
 
     // keep React up-to-date with 
-    const source = new EventSource("http://162.232.250.170/api/events");
-    source.addEventListener("offline",  () => setMcLastEvent("offline"));
+    const source = new EventSource("http://162.232.250.170/api/events", { withCredentials: true });
+    source.addEventListener("offline",  () => { setMcLastEvent("offline"); setPlayers([]); });
     source.addEventListener("starting", () => setMcLastEvent("starting"));
+    source.addEventListener("stopping", () => setMcLastEvent("stopping"));
     source.addEventListener("online",   () => setMcLastEvent("online"));
     source.addEventListener("empty",    () => setMcLastEvent("empty"));
     source.addEventListener("occupied", () => setMcLastEvent("occupied"));
     source.addEventListener("crashed",  () => setMcLastEvent("crashed"));
+    source.addEventListener("crash_loop", () => setMcLastEvent("crash_loop"));
+    // some events were missed while reconnecting, so start over from the current state
+    source.addEventListener("resync", (e) => {
+      setMcLastEvent(JSON.parse(e.data).state);
+      setPlayers([]);
+    });
+    source.addEventListener("player_joined", (e) => {
+      const { name } = JSON.parse(e.data);
+      setPlayers((players) => [...players.filter((p) => p !== name), name]);
+    });
+    source.addEventListener("player_left", (e) => {
+      const { name } = JSON.parse(e.data);
+      setPlayers((players) => players.filter((p) => p !== name));
+    });
     
     // Error occurs:
     // source.onerror
@@ -48,18 +76,22 @@ function App() {
       : (mc_last_event === "online" || mc_last_event === "empty" || mc_last_event === "occupied"
         ? <>
             <p>most recent event: <i>{mc_last_event}</i></p>
+            {players.length > 0 &&
+              <p>players online: {players.join(", ")}</p>}
             <QueryData></QueryData>
           </>
         : <>
             <p>most recent event: <i>{mc_last_event}</i></p>
             <h2>Server is offline</h2>
             <button onClick={startServer}>Start Minecraft!</button>
+            {start_err && <p>{start_err}</p>}
           </>
     )));
 
   return (
     <main>
       <h1>Minecraft Server Viewer</h1>
+      <Login user={user} setUser={setUser}></Login>
       {content}
     </main>
   );
@@ -0,0 +1,61 @@
+import React, { useState, useEffect } from 'react';
+
+// The session is a cookie, so every request has to include credentials for it to be sent along
+// when the UI is served from another origin (see CORS_ORIGIN)
+function Login({ user, setUser }) {
+  const [username, setUsername] = useState("");
+  const [password, setPassword] = useState("");
+  const [error, setError] = useState(null);
+
+  useEffect(() => {
+    fetch("http://162.232.250.170/api/whoami", { credentials: "include" })
+      .then(res => res.ok ? res.json() : null)
+      .then(res_json => setUser(res_json))
+      .catch(() => setUser(null));
+  }, [setUser]);
+
+  const login = async (e) => {
+    e.preventDefault();
+
+    const res = await fetch("http://162.232.250.170/api/login", {
+      method: "POST",
+      credentials: "include",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ username, password }),
+    });
+
+    if (res.ok) {
+      setUser(await res.json());
+      setPassword("");
+      setError(null);
+    } else if (res.status === 429) {
+      setError(`Too many failed attempts, try again in ${res.headers.get("Retry-After")} seconds`);
+    } else {
+      setError("Wrong username or password");
+    }
+  };
+
+  const logout = async () => {
+    await fetch("http://162.232.250.170/api/logout", { method: "POST", credentials: "include" });
+    setUser(null);
+  };
+
+  if (user && user.name !== "anonymous") {
+    return (
+      <p>
+        Logged in as {user.name} ({user.role}) <button onClick={logout}>Log out</button>
+      </p>
+    );
+  }
+
+  return (
+    <form onSubmit={login}>
+      <input placeholder="username" value={username} onChange={(e) => setUsername(e.target.value)} />
+      <input placeholder="password" type="password" value={password} onChange={(e) => setPassword(e.target.value)} />
+      <button type="submit">Log in</button>
+      {error && <p>{error}</p>}
+    </form>
+  );
+}
+
+export default Login;
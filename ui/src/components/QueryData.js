@@ -2,6 +2,7 @@ import useFetch from 'react-fetch-hook';
 
 function QueryData() {
 	const { isLoading, data, error } = useFetch("http://162.232.250.170/api/query", {
+	  credentials: "include",
 	  formatter: (response) => response.json()
 	})
   
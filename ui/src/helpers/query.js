@@ -2,7 +2,7 @@
 
 function getLastEvent(setStatus, setError, setLoading) {
 	setLoading(true);
-  fetch("http://162.232.250.170/api/last-event")
+  fetch("http://162.232.250.170/api/last-event", { credentials: "include" })
     .then(res => {
       if (res.ok) {
         return res.json();